@@ -0,0 +1,136 @@
+use crate::devices::disk::{Disk, DiskSectionType};
+use crate::error::VmError;
+use crate::util::{convert_i16_to_i32, convert_i32_to_i16};
+use crate::vm::Machine;
+use std::fmt;
+//Where each fn of a build landed in memory. Executable::build stores it in a Symbols disk
+//section, set_disk loads it back so backtraces can name the frames.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolMap {
+    //(start, len, name), sorted by start
+    fns: Vec<(usize, usize, String)>,
+}
+impl SymbolMap {
+    pub fn new() -> SymbolMap {
+        SymbolMap { fns: vec![] }
+    }
+    pub fn add(&mut self, start: usize, len: usize, name: &str) {
+        let at = self.fns.partition_point(|(s, _, _)| *s < start);
+        self.fns.insert(at, (start, len, name.to_string()));
+    }
+    //[start:i32, len:i32, name len, name bytes...] per fn
+    pub fn serialize(&self) -> Vec<i16> {
+        let mut data = vec![];
+        for (start, len, name) in &self.fns {
+            data.extend(convert_i32_to_i16(*start as i32));
+            data.extend(convert_i32_to_i16(*len as i32));
+            data.push(name.len() as i16);
+            data.extend(name.bytes().map(|b| b as i16));
+        }
+        data
+    }
+    fn deserialize(&mut self, data: &[i16]) {
+        let mut i = 0;
+        while i + 5 <= data.len() {
+            let start = convert_i16_to_i32(&data[i..i + 2]) as usize;
+            let len = convert_i16_to_i32(&data[i + 2..i + 4]) as usize;
+            let name_len = data[i + 4] as usize;
+            let name = match data.get(i + 5..i + 5 + name_len) {
+                Some(name) => name.iter().map(|b| *b as u8).collect::<Vec<u8>>(),
+                None => break,
+            };
+            self.add(start, len, &String::from_utf8_lossy(&name));
+            i += 5 + name_len;
+        }
+    }
+    //every Symbols section on the disk, one per build written to it
+    pub fn from_disk(disk: &Disk) -> SymbolMap {
+        let mut map = SymbolMap::new();
+        for section in disk {
+            if section.section_type == DiskSectionType::Symbols {
+                map.deserialize(&section.data);
+            }
+        }
+        map
+    }
+    //the fn containing addr and how far into it addr is
+    pub fn resolve(&self, addr: usize) -> Option<(&str, usize)> {
+        let at = self.fns.partition_point(|(s, _, _)| *s <= addr);
+        let (start, len, name) = self.fns.get(at.checked_sub(1)?)?;
+        (addr < start + len).then_some((name.as_str(), addr - start))
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub ip: usize,
+    //fn name and offset, None outside of any known fn
+    pub func: Option<(String, usize)>,
+}
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.func {
+            Some((name, offset)) => write!(f, "%{:07} {}+{}", self.ip, name, offset),
+            None => write!(f, "%{:07} ??", self.ip),
+        }
+    }
+}
+//The current IP, then the return address of every frame Call pushed, innermost first.
+//Walks the saved ARPs, one per call the stack says is still open. After a fault IP has
+//already moved past the faulting instruction, like return addresses are past their Call,
+//so those are looked up one word back in case the instruction was the last one of its fn.
+pub fn backtrace(machine: &Machine, faulted: bool) -> Vec<Frame> {
+    let base = machine.memory.stack_base();
+    let stack = &machine.core.stack;
+    let mut frames = vec![frame_at(machine, machine.core.ip, faulted)];
+    let mut arp = machine.core.arp;
+    for _ in 0..stack.depth {
+        let frame = match arp.checked_sub(base) {
+            Some(frame) => frame,
+            None => break,
+        };
+        match (stack.get(frame, 2), stack.get(frame + 2, 2)) {
+            (Some(prev_arp), Some(ret_ip)) => {
+                frames.push(frame_at(machine, convert_i16_to_i32(ret_ip) as usize, true));
+                arp = convert_i16_to_i32(prev_arp) as usize;
+            }
+            _ => break,
+        }
+    }
+    frames
+}
+fn frame_at(machine: &Machine, ip: usize, past: bool) -> Frame {
+    let addr = if past { ip.saturating_sub(1) } else { ip };
+    Frame {
+        ip,
+        func: machine.symbols.resolve(addr).map(|(name, offset)| {
+            //offsets stay relative to the address shown
+            (name.to_string(), offset + ip - addr)
+        }),
+    }
+}
+pub fn print_backtrace(frames: &[Frame]) {
+    print!("{}", Backtrace(frames));
+}
+struct Backtrace<'a>(&'a [Frame]);
+impl fmt::Display for Backtrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, frame) in self.0.iter().enumerate() {
+            writeln!(f, "  #{} {}", i, frame)?;
+        }
+        Ok(())
+    }
+}
+//A fault with the guest frames at the time, see Machine::fault_report. Displays as the error
+//followed by the backtrace.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultReport {
+    pub error: VmError,
+    pub trace: Vec<Frame>,
+}
+impl fmt::Display for FaultReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        write!(f, "{}", Backtrace(&self.trace))
+    }
+}
@@ -0,0 +1,128 @@
+use crate::devices::disk::{Disk, DiskSection, DiskSectionType};
+use crate::executable::{Bytecode, Executable, Fn};
+use crate::vm::CommandType::*;
+use crate::threaded::Backend;
+use crate::vm::Machine;
+use std::time::{Duration, Instant};
+//address of the counter the inner loop round-trips through memory
+const COUNTER_ADDR: i32 = 100_000;
+const INNER: i16 = 10_000;
+const OUTER: i16 = 200;
+//Tight arithmetic/memory/branch loop, ~8 instructions per inner iteration
+fn bench_exe() -> Executable {
+    let mut exe = Executable::new();
+    let mut main_fn = Fn::new("main".to_string(), 0);
+    main_fn.add_block(
+        vec![
+            Bytecode::Command(Mov),
+            Bytecode::Int(0),
+            Bytecode::Register(R2),
+            Bytecode::Command(Mov),
+            Bytecode::Int(0),
+            Bytecode::Register(R3),
+        ],
+        true,
+    );
+    let inner = main_fn.add_block(
+        vec![
+            Bytecode::Command(Add),
+            Bytecode::Register(R2),
+            Bytecode::Int(1),
+            Bytecode::Command(Mov),
+            Bytecode::Register(R1),
+            Bytecode::Register(R2),
+            Bytecode::Command(Store),
+            Bytecode::Int32(COUNTER_ADDR),
+            Bytecode::Register(R2),
+            Bytecode::Command(Load),
+            Bytecode::Int32(COUNTER_ADDR),
+            Bytecode::Register(R4),
+            Bytecode::Command(Mul),
+            Bytecode::Register(R4),
+            Bytecode::Int(3),
+            Bytecode::Command(Xor),
+            Bytecode::Register(R1),
+            Bytecode::Register(R5),
+            Bytecode::Command(LessThan),
+            Bytecode::Register(R4),
+            Bytecode::Int(INNER),
+            Bytecode::Command(JumpNotZero),
+            Bytecode::BlockLoc(1),
+            Bytecode::Register(R1),
+        ],
+        false,
+    );
+    main_fn.add_block(
+        vec![
+            Bytecode::Command(Mov),
+            Bytecode::Int(0),
+            Bytecode::Register(R2),
+            Bytecode::Command(Add),
+            Bytecode::Register(R3),
+            Bytecode::Int(1),
+            Bytecode::Command(Mov),
+            Bytecode::Register(R1),
+            Bytecode::Register(R3),
+            Bytecode::Command(LessThan),
+            Bytecode::Register(R3),
+            Bytecode::Int(OUTER),
+            Bytecode::Command(JumpNotZero),
+            Bytecode::BlockLoc(inner),
+            Bytecode::Register(R1),
+            Bytecode::Command(Exit),
+        ],
+        false,
+    );
+    exe.add_fn(main_fn);
+    exe
+}
+const RUNS: usize = 5;
+fn run_once(icache: bool, backend: Backend) -> (u64, Duration) {
+    let mut disk: Disk = vec![DiskSection {
+        section_type: DiskSectionType::Entrypoint,
+        id: 0,
+        data: vec![],
+    }] as Disk;
+    bench_exe().build(0, &mut disk, false);
+    let mut machine = Machine::new_headless(false);
+    machine.memory.icache.enabled = icache;
+    machine.backend = backend;
+    machine.clock_hz = None;
+    machine.set_disk(disk);
+    let start = Instant::now();
+    machine.run();
+    let elapsed = start.elapsed();
+    if machine.fault.is_some() || machine.core.r3 != OUTER {
+        panic!("Benchmark program failed: {:?}", machine.fault);
+    }
+    (machine.freq.0, elapsed)
+}
+//best of RUNS, so other load on the host doesn't skew the ratio
+fn run_best(name: &str, icache: bool, backend: Backend) -> Duration {
+    let mut best = Duration::MAX;
+    let mut instructions = 0;
+    for _ in 0..RUNS {
+        let (count, elapsed) = run_once(icache, backend);
+        instructions = count;
+        best = best.min(elapsed);
+    }
+    println!(
+        "  {:<12} {:>10} instructions in {:>8.3}s, {:.2} MIPS",
+        name,
+        instructions,
+        best.as_secs_f64(),
+        instructions as f64 / best.as_secs_f64() / 1e6
+    );
+    best
+}
+pub fn run_bench() {
+    println!("Interpreter benchmark (best of {}):", RUNS);
+    let uncached = run_best("uncached", false, Backend::Interpreter);
+    let cached = run_best("icache", true, Backend::Interpreter);
+    let threaded = run_best("threaded", true, Backend::Threaded);
+    println!(
+        "Speedup over uncached: icache {:.1}x, threaded {:.1}x",
+        uncached.as_secs_f64() / cached.as_secs_f64(),
+        uncached.as_secs_f64() / threaded.as_secs_f64()
+    );
+}
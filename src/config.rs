@@ -0,0 +1,205 @@
+use crate::devices::DeviceKind;
+use crate::memmap::{LOADER_LEN, MMIO_LEN, MMIO_SLOT_LEN};
+use crate::vm::{DEFAULT_STACK_LIMIT, Machine};
+use minifb::Scale;
+use serde::Deserialize;
+use std::{fmt, fs};
+//Guest addresses are i32s and the stack lives above memory, so memory stays well below that
+const MAX_MEMORY: usize = 1 << 30;
+//one MMIO slot each
+pub const MAX_DEVICES: usize = MMIO_LEN / MMIO_SLOT_LEN;
+//The hardware a Machine is built with. As TOML every key is optional:
+//  memory = 4194304                                    words, the stack starts right after
+//  stack_limit = 1048576                               words per core and coroutine
+//  devices = ["disk", "audio", "clock", "gfx", "perf"] one per slot, at most 16, the disk has to
+//                                                      be first, "host" fixes the host call device's
+//                                                      slot
+//  resolution = [320, 240]                             multiples of 8, the tile size
+//  scale = 4                                           window scale, 1 2 4 8 16 or 32
+//  sample_rate = 32000
+//  headless = false                                    no window or audio output
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub memory: usize,
+    pub stack_limit: usize,
+    pub devices: Vec<DeviceKind>,
+    pub resolution: [u32; 2],
+    pub scale: u32,
+    pub sample_rate: u32,
+    pub headless: bool,
+}
+impl Default for MachineConfig {
+    fn default() -> MachineConfig {
+        MachineConfig {
+            memory: 4 * 1024 * 1024,
+            stack_limit: DEFAULT_STACK_LIMIT,
+            devices: vec![
+                DeviceKind::Disk,
+                DeviceKind::Audio,
+                DeviceKind::Clock,
+                DeviceKind::Gfx,
+                DeviceKind::Perf,
+            ],
+            resolution: [320, 240],
+            scale: 4,
+            sample_rate: 32000,
+            headless: false,
+        }
+    }
+}
+impl MachineConfig {
+    pub fn from_toml(text: &str) -> Result<MachineConfig, ConfigError> {
+        let config: MachineConfig =
+            toml::from_str(text).map_err(|err| ConfigError::Parse(err.message().to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+    pub fn load(path: &str) -> Result<MachineConfig, ConfigError> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(path.to_string(), err.to_string()))?;
+        MachineConfig::from_toml(&text)
+    }
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        //the boot sector loads the rest of the program through IO 0
+        if self.devices.first() != Some(&DeviceKind::Disk) {
+            return Err(ConfigError::DiskSlot);
+        }
+        if self.devices.len() > MAX_DEVICES {
+            return Err(ConfigError::Devices(self.devices.len()));
+        }
+        if self.memory <= LOADER_LEN + MMIO_LEN || self.memory > MAX_MEMORY {
+            return Err(ConfigError::Memory(self.memory));
+        }
+        if self.stack_limit == 0 || self.stack_limit > MAX_MEMORY {
+            return Err(ConfigError::StackLimit(self.stack_limit));
+        }
+        let [width, height] = self.resolution;
+        if width == 0 || height == 0 || width % 8 != 0 || height % 8 != 0 {
+            return Err(ConfigError::Resolution(self.resolution));
+        }
+        if window_scale(self.scale).is_none() {
+            return Err(ConfigError::Scale(self.scale));
+        }
+        if self.sample_rate == 0 {
+            return Err(ConfigError::SampleRate);
+        }
+        Ok(())
+    }
+}
+pub fn window_scale(factor: u32) -> Option<Scale> {
+    match factor {
+        1 => Some(Scale::X1),
+        2 => Some(Scale::X2),
+        4 => Some(Scale::X4),
+        8 => Some(Scale::X8),
+        16 => Some(Scale::X16),
+        32 => Some(Scale::X32),
+        _ => None,
+    }
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    //the file couldn't be read, (path, reason)
+    Io(String, String),
+    //not valid TOML, or a key that isn't in MachineConfig
+    Parse(String),
+    //slot 0 isn't the disk
+    DiskSlot,
+    //more devices than the MMIO window has slots for
+    Devices(usize),
+    //too small to hold the loader and the MMIO window, or too big to address
+    Memory(usize),
+    StackLimit(usize),
+    Resolution([u32; 2]),
+    Scale(u32),
+    SampleRate,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "Couldn't read {}: {}", path, err),
+            ConfigError::Parse(err) => write!(f, "Invalid config: {}", err),
+            ConfigError::DiskSlot => write!(f, "The first device has to be the disk"),
+            ConfigError::Devices(count) => {
+                write!(f, "At most {} devices fit, got {}", MAX_DEVICES, count)
+            }
+            ConfigError::Memory(words) => write!(
+                f,
+                "Memory must be more than {} and at most {} words, got {}",
+                LOADER_LEN + MMIO_LEN,
+                MAX_MEMORY,
+                words
+            ),
+            ConfigError::StackLimit(words) => write!(
+                f,
+                "Stack limit must be more than 0 and at most {} words, got {}",
+                MAX_MEMORY, words
+            ),
+            ConfigError::Resolution([width, height]) => write!(
+                f,
+                "Resolution {}x{} isn't a non-zero multiple of 8",
+                width, height
+            ),
+            ConfigError::Scale(scale) => {
+                write!(f, "Scale {} isn't one of 1, 2, 4, 8, 16 or 32", scale)
+            }
+            ConfigError::SampleRate => write!(f, "Sample rate can't be 0"),
+        }
+    }
+}
+//Builds a Machine for a hardware profile, starting from the default one
+#[derive(Debug, Clone, Default)]
+pub struct MachineBuilder {
+    config: MachineConfig,
+    debug: bool,
+}
+impl MachineBuilder {
+    pub fn new() -> MachineBuilder {
+        MachineBuilder::default()
+    }
+    pub fn from_config(config: MachineConfig) -> MachineBuilder {
+        MachineBuilder {
+            config,
+            debug: false,
+        }
+    }
+    pub fn debug(mut self, debug: bool) -> MachineBuilder {
+        self.debug = debug;
+        self
+    }
+    //in words
+    pub fn memory(mut self, words: usize) -> MachineBuilder {
+        self.config.memory = words;
+        self
+    }
+    //in words, for every core and coroutine
+    pub fn stack_limit(mut self, words: usize) -> MachineBuilder {
+        self.config.stack_limit = words;
+        self
+    }
+    pub fn devices(mut self, devices: Vec<DeviceKind>) -> MachineBuilder {
+        self.config.devices = devices;
+        self
+    }
+    pub fn resolution(mut self, width: u32, height: u32) -> MachineBuilder {
+        self.config.resolution = [width, height];
+        self
+    }
+    pub fn scale(mut self, scale: u32) -> MachineBuilder {
+        self.config.scale = scale;
+        self
+    }
+    pub fn sample_rate(mut self, sample_rate: u32) -> MachineBuilder {
+        self.config.sample_rate = sample_rate;
+        self
+    }
+    pub fn headless(mut self, headless: bool) -> MachineBuilder {
+        self.config.headless = headless;
+        self
+    }
+    pub fn build(self) -> Result<Machine, ConfigError> {
+        self.config.validate()?;
+        Ok(Machine::with_config(self.debug, &self.config))
+    }
+}
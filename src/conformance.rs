@@ -0,0 +1,2512 @@
+//Headless per-opcode tests. Every program runs on each backend and has to leave the same state.
+use crate::config::{ConfigError, MachineBuilder, MachineConfig};
+use crate::devices::audio::AudioDevice;
+use crate::devices::disk::{Disk, DiskSection, DiskSectionType};
+use crate::devices::host::{HOST_BAD_ARGS, HOST_NO_FUNCTION, HOST_OK};
+use crate::devices::{self, CAP_MMIO, CUSTOM_DEVICE, DEVICE_TICK, Device, DeviceKind};
+use crate::error::{Access, VmError};
+use crate::executable::{Bytecode, Bytecode::*, Data, Executable, Fn};
+use crate::memmap::{MemoryMap, Perms, Region, RegionKind};
+use crate::threaded::Backend;
+use crate::util::{convert_i16_to_i32, get_reg, pack_command, unpack_float};
+use crate::vm::CommandType::*;
+use crate::vm::{DEFAULT_CLOCK_HZ, DEFAULT_STACK_LIMIT, DataType, Machine, decode_with};
+use std::time::Duration;
+//scratch address in the heap, well above any test's constants
+const HEAP: i32 = 100_000;
+const STACK_BASE: usize = 4 * 1024 * 1024;
+const BACKENDS: [Backend; 2] = [Backend::Interpreter, Backend::Threaded];
+
+//unthrottled, ready to run
+fn load_exe(exe: Executable, backend: Backend) -> Machine {
+    load_exe_on(Machine::new_headless(false), exe, backend)
+}
+fn load_exe_on(mut machine: Machine, exe: Executable, backend: Backend) -> Machine {
+    let mut disk: Disk = vec![DiskSection {
+        section_type: DiskSectionType::Entrypoint,
+        id: 0,
+        data: vec![],
+    }] as Disk;
+    exe.build(0, &mut disk, false);
+    machine.backend = backend;
+    machine.clock_hz = None;
+    machine.set_disk(disk);
+    machine
+}
+fn run_exe(exe: Executable, backend: Backend) -> Machine {
+    let mut machine = load_exe(exe, backend);
+    machine.run();
+    machine
+}
+fn main_exe(blocks: Vec<Vec<Bytecode>>) -> Executable {
+    let mut exe = Executable::new();
+    exe.add_fn(Fn::new_with_blocks("main".to_string(), 0, blocks));
+    exe
+}
+fn check_exe(build: impl std::ops::Fn() -> Executable, check: impl std::ops::Fn(&Machine)) {
+    for backend in BACKENDS {
+        let machine = run_exe(build(), backend);
+        assert_eq!(machine.fault, None, "faulted on {:?}", backend);
+        assert!(!machine.on, "didn't reach Exit on {:?}", backend);
+        check(&machine);
+    }
+}
+//main's first block is the entrypoint, blocks end themselves
+fn check_blocks(blocks: Vec<Vec<Bytecode>>, check: impl std::ops::Fn(&Machine)) {
+    check_exe(|| main_exe(blocks.clone()), check);
+}
+//a single straight-line block, Exit is appended
+fn check(code: Vec<Bytecode>, check: impl std::ops::Fn(&Machine)) {
+    let mut code = code;
+    code.push(Command(Exit));
+    check_blocks(vec![code], check);
+}
+fn expect_fault(code: Vec<Bytecode>) -> VmError {
+    let mut code = code;
+    code.push(Command(Exit));
+    let mut faults = BACKENDS.map(|backend| run_exe(main_exe(vec![code.clone()]), backend).fault);
+    assert_eq!(faults[0], faults[1], "backends disagree");
+    faults[0].take().expect("program didn't fault")
+}
+fn read(machine: &Machine, addr: i32) -> i16 {
+    machine.memory.read(addr as usize, machine)
+}
+fn read_i32(machine: &Machine, addr: i32) -> i32 {
+    convert_i16_to_i32(&machine.memory.read_range(addr as usize..addr as usize + 2, machine))
+}
+fn read_f32(machine: &Machine, addr: i32) -> f32 {
+    unpack_float(&machine.memory.read_range(addr as usize..addr as usize + 2, machine)).unwrap()
+}
+
+//operand encodings
+#[test]
+fn immediate_operand() {
+    check(vec![Command(Add), Int(-7), Int(3)], |m| assert_eq!(m.core.r1, -4));
+}
+#[test]
+fn register_operand() {
+    check(
+        vec![Command(Mov), Int(9), Register(R4), Command(Add), Register(R4), Register(R4)],
+        |m| assert_eq!(m.core.r1, 18),
+    );
+}
+#[test]
+fn i32_operand() {
+    check(vec![Command(AddEx), Int32(100_000), Int32(-1)], |m| {
+        assert_eq!(get_reg(10, &m.core), 99_999.0)
+    });
+}
+#[test]
+fn float_operand() {
+    check(vec![Command(Addf), Float(1.25), Float(-0.5)], |m| {
+        assert_eq!(m.core.f1, 0.75)
+    });
+}
+#[test]
+fn reg_offset_operand() {
+    check(
+        vec![
+            Command(Mov),
+            Int32(HEAP),
+            Register(EX1),
+            Command(Store),
+            RegOffset(EX1, 3),
+            Int(5),
+            Command(Load),
+            RegOffset(EX1, 3),
+            Register(R4),
+            Command(StoreEx),
+            RegOffset(EX1, -2),
+            Int32(-90_000),
+            Command(Mov),
+            RegOffset(R4, -10),
+            Register(R5),
+        ],
+        |m| {
+            assert_eq!(read(m, HEAP + 3), 5);
+            assert_eq!(m.core.r4, 5);
+            assert_eq!(read_i32(m, HEAP - 2), -90_000);
+            assert_eq!(m.core.r5, -5);
+        },
+    );
+}
+#[test]
+fn literal_min_operand() {
+    //i16::MIN is the escape word, Int packs it as an escaped literal
+    check(
+        vec![
+            Command(Mov),
+            Int(i16::MIN),
+            Register(R4),
+            Command(Add),
+            Int(i16::MIN),
+            Int(1),
+        ],
+        |m| {
+            assert_eq!(m.core.r4, i16::MIN);
+            assert_eq!(m.core.r1, i16::MIN + 1);
+        },
+    );
+}
+#[test]
+fn unknown_operand_tag() {
+    let code = [pack_command(Add), 1, i16::MIN, 9, 0, 0];
+    let err = decode_with(0, |addr| Ok(code[addr])).unwrap_err();
+    assert_eq!(err, VmError::InvalidOperand { addr: 2, tag: 9 });
+}
+#[test]
+fn every_register_round_trips() {
+    check(
+        vec![
+            Command(Mov),
+            Int(1),
+            Register(R1),
+            Command(Mov),
+            Int(2),
+            Register(R2),
+            Command(Mov),
+            Int(3),
+            Register(R3),
+            Command(Mov),
+            Int(4),
+            Register(R4),
+            Command(Mov),
+            Int(5),
+            Register(R5),
+            Command(Mov),
+            Float(1.5),
+            Register(F1),
+            Command(Mov),
+            Float(-2.5),
+            Register(F2),
+        ],
+        |m| {
+            assert_eq!(
+                [m.core.r1, m.core.r2, m.core.r3, m.core.r4, m.core.r5],
+                [1, 2, 3, 4, 5]
+            );
+            assert_eq!((m.core.f1, m.core.f2), (1.5, -2.5));
+        },
+    );
+}
+#[test]
+fn extended_registers_span_two_words() {
+    check(
+        vec![
+            Command(Mov),
+            Int32(-123_456),
+            Register(EX1),
+            Command(Mov),
+            Int32(654_321),
+            Register(EX2),
+        ],
+        |m| {
+            assert_eq!(get_reg(10, &m.core), -123_456.0);
+            assert_eq!(get_reg(11, &m.core), 654_321.0);
+        },
+    );
+}
+#[test]
+fn ip_operand_reads_past_the_command() {
+    //operands see IP one word past the command
+    check(vec![Command(Mov), Register(IP), Register(EX2)], |m| {
+        let mov_at = get_reg(11, &m.core) as usize - 1;
+        assert_eq!(read(m, mov_at as i32), 17); //Mov's opcode
+    });
+}
+#[test]
+fn sp_and_srp_registers() {
+    check(
+        vec![
+            Command(Push),
+            Int(1),
+            Command(Push),
+            Int(2),
+            Command(Mov),
+            Register(SP),
+            Register(R4),
+            Command(Mov),
+            Register(SRP),
+            Register(R5),
+        ],
+        |m| {
+            assert_eq!(m.core.r4, 2);
+            assert_eq!(m.core.r5, 2);
+        },
+    );
+}
+#[test]
+fn writing_sp_resizes_the_stack() {
+    check(vec![Command(Push), Int(1), Command(Mov), Int(5), Register(SP)], |m| {
+        assert_eq!(m.core.stack.len(), 5);
+    });
+}
+
+//i16 arithmetic, results in r1
+#[test]
+fn add() {
+    check(vec![Command(Add), Int(2), Int(3)], |m| assert_eq!(m.core.r1, 5));
+}
+#[test]
+fn sub() {
+    check(vec![Command(Sub), Int(2), Int(3)], |m| assert_eq!(m.core.r1, -1));
+}
+#[test]
+fn mul() {
+    check(vec![Command(Mul), Int(-6), Int(7)], |m| assert_eq!(m.core.r1, -42));
+}
+#[test]
+fn div() {
+    check(vec![Command(Div), Int(7), Int(2)], |m| assert_eq!(m.core.r1, 3));
+}
+#[test]
+fn modulo() {
+    check(vec![Command(Mod), Int(17), Int(5)], |m| assert_eq!(m.core.r1, 2));
+}
+#[test]
+fn and() {
+    check(vec![Command(And), Int(0b1100), Int(0b1010)], |m| {
+        assert_eq!(m.core.r1, 0b1000)
+    });
+}
+#[test]
+fn or() {
+    check(vec![Command(Or), Int(0b1100), Int(0b1010)], |m| {
+        assert_eq!(m.core.r1, 0b1110)
+    });
+}
+#[test]
+fn xor() {
+    check(vec![Command(Xor), Int(0b1100), Int(0b1010)], |m| {
+        assert_eq!(m.core.r1, 0b0110)
+    });
+}
+#[test]
+fn not() {
+    check(vec![Command(Not), Int(0)], |m| assert_eq!(m.core.r1, -1));
+}
+#[test]
+fn greater() {
+    check(
+        vec![
+            Command(Greater),
+            Int(3),
+            Int(2),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(Greater),
+            Int(2),
+            Int(3),
+        ],
+        |m| assert_eq!((m.core.r4, m.core.r1), (1, 0)),
+    );
+}
+#[test]
+fn less_than() {
+    check(
+        vec![
+            Command(LessThan),
+            Int(2),
+            Int(3),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(LessThan),
+            Int(3),
+            Int(3),
+        ],
+        |m| assert_eq!((m.core.r4, m.core.r1), (1, 0)),
+    );
+}
+
+//f32 arithmetic, results in f1
+#[test]
+fn addf() {
+    check(vec![Command(Addf), Float(0.5), Float(0.25)], |m| {
+        assert_eq!(m.core.f1, 0.75)
+    });
+}
+#[test]
+fn subf() {
+    check(vec![Command(Subf), Float(0.5), Float(0.25)], |m| {
+        assert_eq!(m.core.f1, 0.25)
+    });
+}
+#[test]
+fn mulf() {
+    check(vec![Command(Mulf), Float(1.5), Float(-2.0)], |m| {
+        assert_eq!(m.core.f1, -3.0)
+    });
+}
+#[test]
+fn divf() {
+    check(vec![Command(Divf), Float(1.0), Float(4.0)], |m| {
+        assert_eq!(m.core.f1, 0.25)
+    });
+}
+#[test]
+fn sqrt() {
+    check(vec![Command(Sqrt), Float(2.25)], |m| assert_eq!(m.core.f1, 1.5));
+}
+#[test]
+fn sin_cos() {
+    check(
+        vec![
+            Command(Sin),
+            Float(std::f32::consts::FRAC_PI_2),
+            Command(Mov),
+            Register(F1),
+            Register(F2),
+            Command(Cos),
+            Float(0.0),
+        ],
+        |m| {
+            assert_eq!(m.core.f2, 1.0);
+            assert_eq!(m.core.f1, 1.0);
+        },
+    );
+}
+#[test]
+fn atan2() {
+    check(vec![Command(Atan2), Float(1.0), Float(-1.0)], |m| {
+        assert_eq!(m.core.f1, 1.0f32.atan2(-1.0))
+    });
+}
+#[test]
+fn pow() {
+    check(vec![Command(Pow), Float(2.0), Float(-2.0)], |m| {
+        assert_eq!(m.core.f1, 0.25)
+    });
+}
+#[test]
+fn floor() {
+    check(vec![Command(Floor), Float(-1.5)], |m| assert_eq!(m.core.f1, -2.0));
+}
+#[test]
+fn absf() {
+    check(vec![Command(Absf), Float(-0.75)], |m| assert_eq!(m.core.f1, 0.75));
+}
+#[test]
+fn minf_maxf() {
+    check(
+        vec![
+            Command(Minf),
+            Float(-0.5),
+            Float(3.0),
+            Command(Mov),
+            Register(F1),
+            Register(F2),
+            Command(Maxf),
+            Float(-0.5),
+            Float(3.0),
+        ],
+        |m| {
+            assert_eq!(m.core.f2, -0.5);
+            assert_eq!(m.core.f1, 3.0);
+        },
+    );
+}
+#[test]
+fn float_compares() {
+    //f1 holds 0.1 at f32 precision, Equalf compares both sides at that precision
+    check(
+        vec![
+            Command(Addf),
+            Float(0.1),
+            Float(0.0),
+            Command(Equalf),
+            Register(F1),
+            Float(0.1),
+            Command(Mov),
+            Register(R1),
+            Register(R2),
+            Command(Greaterf),
+            Float(-1.0),
+            Float(2.0),
+            Command(Mov),
+            Register(R1),
+            Register(R3),
+            Command(LessThanf),
+            Float(-1.0),
+            Float(2.0),
+        ],
+        |m| {
+            assert_eq!(m.core.r2, 1);
+            assert_eq!(m.core.r3, 0);
+            assert_eq!(m.core.r1, 1);
+        },
+    );
+}
+#[test]
+fn int_float_conversion() {
+    check(
+        vec![
+            Command(IntToFloat),
+            Int32(-70_000),
+            Register(F2),
+            Command(FloatToInt),
+            Float(-2.75),
+            Register(R4),
+            Command(FloatToInt),
+            Float(123_456.9),
+            Register(EX1),
+        ],
+        |m| {
+            assert_eq!(m.core.f2, -70_000.0);
+            assert_eq!(m.core.r4, -2);
+            assert_eq!(get_reg(10, &m.core), 123_456.0);
+        },
+    );
+}
+#[test]
+fn savef_restoref() {
+    check(
+        vec![
+            Command(Divf),
+            Float(1.0),
+            Float(3.0),
+            Command(Savef),
+            Int32(HEAP),
+            Register(F1),
+            Command(Restoref),
+            Int32(HEAP),
+            Register(F2),
+        ],
+        |m| {
+            assert_eq!(read_f32(m, HEAP), 1.0 / 3.0);
+            assert_eq!(m.core.f2.to_bits(), (1.0f32 / 3.0).to_bits());
+        },
+    );
+}
+
+//i32 arithmetic, results in ex1
+#[test]
+fn add_ex() {
+    check(vec![Command(AddEx), Int32(70_000), Int32(5)], |m| {
+        assert_eq!(get_reg(10, &m.core), 70_005.0)
+    });
+}
+#[test]
+fn sub_ex() {
+    check(vec![Command(SubEx), Int32(5), Int32(70_000)], |m| {
+        assert_eq!(get_reg(10, &m.core), -69_995.0)
+    });
+}
+#[test]
+fn mul_ex() {
+    check(vec![Command(MulEx), Int32(1_000), Int32(1_000)], |m| {
+        assert_eq!(get_reg(10, &m.core), 1_000_000.0)
+    });
+}
+#[test]
+fn div_ex() {
+    check(vec![Command(DivEx), Int32(1_000_000), Int32(-4)], |m| {
+        assert_eq!(get_reg(10, &m.core), -250_000.0)
+    });
+}
+#[test]
+fn add_sub_ex_wrap() {
+    check(vec![Command(AddEx), Int32(i32::MAX), Int(1)], |m| {
+        assert_eq!(get_reg(10, &m.core), i32::MIN as f64)
+    });
+    check(vec![Command(SubEx), Int32(i32::MIN), Int(1)], |m| {
+        assert_eq!(get_reg(10, &m.core), i32::MAX as f64)
+    });
+}
+#[test]
+fn mul_div_ex_wrap() {
+    check(vec![Command(MulEx), Int32(0x10000), Int32(0x10001)], |m| {
+        assert_eq!(get_reg(10, &m.core), 0x10000 as f64)
+    });
+    check(vec![Command(DivEx), Int32(i32::MIN), Int32(-1)], |m| {
+        assert_eq!(get_reg(10, &m.core), i32::MIN as f64)
+    });
+}
+#[test]
+fn div_ex_by_zero_faults() {
+    let fault = expect_fault(vec![Command(DivEx), Int32(7), Int(0)]);
+    assert!(matches!(fault, VmError::DivideByZero { .. }));
+}
+#[test]
+fn and_or_xor_ex() {
+    check(
+        vec![
+            Command(AndEx),
+            Int32(0x0F0F_0F0F),
+            Int32(-1 << 16),
+            Command(Mov),
+            Register(EX1),
+            Register(EX2),
+            Command(OrEx),
+            Int32(0x0001_0000),
+            Int32(0x0000_0001),
+        ],
+        |m| {
+            assert_eq!(get_reg(11, &m.core), 0x0F0F_0000 as f64);
+            assert_eq!(get_reg(10, &m.core), 0x0001_0001 as f64);
+        },
+    );
+    check(vec![Command(XorEx), Int32(-1), Int32(0x00FF_00FF)], |m| {
+        assert_eq!(get_reg(10, &m.core), !0x00FF_00FF as f64)
+    });
+}
+#[test]
+fn not_ex() {
+    check(vec![Command(NotEx), Int32(0x7FFF_0000)], |m| {
+        assert_eq!(get_reg(10, &m.core), !0x7FFF_0000 as f64)
+    });
+}
+#[test]
+fn mod_ex() {
+    check(vec![Command(ModEx), Int32(-100_003), Int32(1_000)], |m| {
+        assert_eq!(get_reg(10, &m.core), -3.0)
+    });
+    check(vec![Command(ModEx), Int32(i32::MIN), Int32(-1)], |m| {
+        assert_eq!(get_reg(10, &m.core), 0.0)
+    });
+    let fault = expect_fault(vec![Command(ModEx), Int32(100_003), Int(0)]);
+    assert!(matches!(fault, VmError::DivideByZero { .. }));
+}
+#[test]
+fn shifts_ex() {
+    check(
+        vec![
+            Command(ShlEx),
+            Int(1),
+            Int(31),
+            Command(Mov),
+            Register(EX1),
+            Register(EX2),
+            Command(ShlEx),
+            Int(1),
+            Int(33),
+        ],
+        |m| {
+            assert_eq!(get_reg(11, &m.core), i32::MIN as f64);
+            assert_eq!(get_reg(10, &m.core), 2.0);
+        },
+    );
+    check(vec![Command(ShrEx), Int32(-16), Int(2)], |m| {
+        assert_eq!(get_reg(10, &m.core), -4.0)
+    });
+    check(vec![Command(ShrUEx), Int32(-1), Int(28)], |m| {
+        assert_eq!(get_reg(10, &m.core), 15.0)
+    });
+}
+#[test]
+fn compares_ex() {
+    check(
+        vec![
+            Command(GreaterEx),
+            Int32(70_000),
+            Int32(-70_000),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(LessThanEx),
+            Int32(70_000),
+            Int32(-70_000),
+            Command(Mov),
+            Register(R1),
+            Register(R5),
+            Command(EqualEx),
+            Int32(70_000),
+            Int32(70_000),
+        ],
+        |m| {
+            assert_eq!(m.core.r4, 1);
+            assert_eq!(m.core.r5, 0);
+            assert_eq!(m.core.r1, 1);
+        },
+    );
+}
+
+//stack
+#[test]
+fn push_pop() {
+    check(vec![Command(Push), Int(-5), Command(Pop), Register(R4)], |m| {
+        assert_eq!(m.core.r4, -5);
+        assert_eq!(m.core.stack.len(), 0);
+    });
+}
+#[test]
+fn push_ex_pop_ex() {
+    check(
+        vec![
+            Command(PushEx),
+            Int32(-200_000),
+            Command(Mov),
+            Register(SP),
+            Register(R1),
+            Command(PopEx),
+            Register(EX2),
+        ],
+        |m| {
+            assert_eq!(m.core.r1, 2, "i32s take two words");
+            assert_eq!(get_reg(11, &m.core), -200_000.0);
+            assert_eq!(m.core.srp, 0);
+        },
+    );
+}
+#[test]
+fn pushf_popf() {
+    check(vec![Command(Pushf), Float(3.5), Command(Popf), Register(F2)], |m| {
+        assert_eq!(m.core.f2, 3.5);
+        assert_eq!(m.core.stack.len(), 0);
+    });
+}
+#[test]
+fn pop_is_last_in_first_out() {
+    check(
+        vec![
+            Command(Push),
+            Int(1),
+            Command(Push),
+            Int(2),
+            Command(Pop),
+            Register(R4),
+            Command(Pop),
+            Register(R5),
+        ],
+        |m| assert_eq!((m.core.r4, m.core.r5), (2, 1)),
+    );
+}
+
+//memory
+#[test]
+fn store_load() {
+    check(
+        vec![Command(Store), Int32(HEAP), Int(-9), Command(Load), Int32(HEAP), Register(R4)],
+        |m| {
+            assert_eq!(read(m, HEAP), -9);
+            assert_eq!(m.core.r4, -9);
+        },
+    );
+}
+#[test]
+fn store_ex_load_ex() {
+    check(
+        vec![
+            Command(StoreEx),
+            Int32(HEAP),
+            Int32(-300_000),
+            Command(LoadEx),
+            Int32(HEAP),
+            Register(EX2),
+        ],
+        |m| {
+            assert_eq!(read_i32(m, HEAP), -300_000);
+            assert_eq!(get_reg(11, &m.core), -300_000.0);
+        },
+    );
+}
+#[test]
+fn storef_loadf() {
+    check(
+        vec![
+            Command(Storef),
+            Int32(HEAP),
+            Float(-1.75),
+            Command(Loadf),
+            Int32(HEAP),
+            Register(F2),
+        ],
+        |m| {
+            assert_eq!(read_f32(m, HEAP), -1.75);
+            assert_eq!(m.core.f2, -1.75);
+        },
+    );
+}
+#[test]
+fn load_constant() {
+    check_exe(
+        || {
+            let mut exe = Executable::new();
+            let constant = exe.add_constant(vec![Data::Int(11), Data::Int32(-70_000)]);
+            let mut main_fn = Fn::new("main".to_string(), 0);
+            main_fn.add_block(
+                vec![
+                    Command(Load),
+                    ConstantLoc(constant),
+                    Register(R1),
+                    Command(AddEx),
+                    ConstantLoc(constant),
+                    Int(1),
+                    Command(LoadEx),
+                    Register(EX1),
+                    Register(EX2),
+                    Command(Exit),
+                ],
+                true,
+            );
+            exe.add_fn(main_fn);
+            exe
+        },
+        |m| {
+            assert_eq!(m.core.r1, 11);
+            assert_eq!(get_reg(11, &m.core), -70_000.0);
+        },
+    );
+}
+#[test]
+fn stack_is_addressable() {
+    check(
+        vec![
+            Command(Push),
+            Int(0),
+            Command(Store),
+            Int32(STACK_BASE as i32),
+            Int(21),
+            Command(Pop),
+            Register(R4),
+        ],
+        |m| assert_eq!(m.core.r4, 21),
+    );
+}
+#[test]
+fn mov() {
+    check(vec![Command(Mov), Int(12), Register(R4), Command(Mov), Register(R4), Register(R5)], |m| {
+        assert_eq!((m.core.r4, m.core.r5), (12, 12))
+    });
+}
+#[test]
+fn nop() {
+    check(vec![Command(Mov), Int(3), Register(R4), Command(NOP)], |m| {
+        assert_eq!(m.core.r4, 3)
+    });
+}
+#[test]
+fn exit_stops_the_machine() {
+    check(vec![Command(Exit), Command(Mov), Int(3), Register(R4)], |m| {
+        assert_eq!(m.core.r4, 0)
+    });
+}
+#[test]
+fn mem_copy() {
+    check(
+        vec![
+            Command(StoreEx),
+            Int32(HEAP),
+            Int32(0x0003_0004),
+            Command(MemCopy),
+            Int32(HEAP),
+            Int32(HEAP + 10),
+            Int(2),
+        ],
+        |m| assert_eq!(read_i32(m, HEAP + 10), 0x0003_0004),
+    );
+}
+#[test]
+fn mem_copy_overlapping() {
+    let fill = |base: i32| {
+        (0..4)
+            .flat_map(|i| [Command(Store), Int32(base + i), Int(i as i16 + 1)])
+            .collect::<Vec<_>>()
+    };
+    //forwards and backwards both behave like memmove
+    let mut code = fill(HEAP);
+    code.extend([Command(MemCopy), Int32(HEAP), Int32(HEAP + 1), Int(4)]);
+    code.extend(fill(HEAP + 10));
+    code.extend([Command(MemCopy), Int32(HEAP + 11), Int32(HEAP + 10), Int(3)]);
+    check(code, |m| {
+        let words = |base: i32| (0..5).map(|i| read(m, base + i)).collect::<Vec<_>>();
+        assert_eq!(words(HEAP), [1, 1, 2, 3, 4]);
+        assert_eq!(words(HEAP + 10)[..4], [2, 3, 4, 4]);
+    });
+}
+#[test]
+fn mem_copy_to_and_from_the_stack() {
+    check(
+        vec![
+            Command(PushEx),
+            Int32(0),
+            Command(Store),
+            Int32(HEAP),
+            Int(8),
+            Command(Store),
+            Int32(HEAP + 1),
+            Int(9),
+            Command(MemCopy),
+            Int32(HEAP),
+            Int32(STACK_BASE as i32),
+            Int(2),
+            Command(MemCopy),
+            Int32(STACK_BASE as i32),
+            Int32(HEAP + 5),
+            Int(2),
+            Command(Pop),
+            Register(R4),
+            Command(Pop),
+            Register(R5),
+        ],
+        |m| {
+            assert_eq!((m.core.r4, m.core.r5), (9, 8));
+            assert_eq!((read(m, HEAP + 5), read(m, HEAP + 6)), (8, 9));
+        },
+    );
+}
+#[test]
+fn mem_copy_past_the_stack_faults() {
+    let fault = expect_fault(vec![
+        Command(Push),
+        Int(0),
+        Command(MemCopy),
+        Int32(HEAP),
+        Int32(STACK_BASE as i32),
+        Int(2),
+    ]);
+    assert!(matches!(fault, VmError::Unmapped { .. }));
+}
+#[test]
+fn mem_set_checks_the_whole_range_first() {
+    //the range runs one word past the top of the stack, so no word gets written
+    let code = vec![
+        Command(Push),
+        Int(0),
+        Command(MemSet),
+        Int32(STACK_BASE as i32),
+        Int(7),
+        Int(2),
+        Command(Exit),
+    ];
+    for backend in BACKENDS {
+        let m = run_exe(main_exe(vec![code.clone()]), backend);
+        let fault = Some(VmError::Unmapped {
+            addr: STACK_BASE + 1,
+            access: Access::Write,
+        });
+        assert_eq!(m.fault, fault, "on {:?}", backend);
+        assert_eq!(m.core.stack.read_bytes(0, 1), [0]);
+    }
+}
+#[test]
+fn mem_set() {
+    check(
+        vec![Command(MemSet), Int32(HEAP + 1), Int(-3), Int(3)],
+        |m| {
+            let words = (0..5).map(|i| read(m, HEAP + i)).collect::<Vec<_>>();
+            assert_eq!(words, [0, -3, -3, -3, 0]);
+        },
+    );
+}
+#[test]
+fn mem_cmp() {
+    check(
+        vec![
+            Command(MemSet),
+            Int32(HEAP),
+            Int(5),
+            Int(4),
+            Command(MemSet),
+            Int32(HEAP + 10),
+            Int(5),
+            Int(4),
+            Command(MemCmp),
+            Int32(HEAP),
+            Int32(HEAP + 10),
+            Int(4),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(Store),
+            Int32(HEAP + 12),
+            Int(-1),
+            Command(MemCmp),
+            Int32(HEAP),
+            Int32(HEAP + 10),
+            Int(4),
+            Command(Mov),
+            Register(R1),
+            Register(R5),
+            Command(MemCmp),
+            Int32(HEAP + 10),
+            Int32(HEAP),
+            Int(4),
+        ],
+        |m| assert_eq!((m.core.r4, m.core.r5, m.core.r1), (0, 1, -1)),
+    );
+}
+#[test]
+fn cycle_counter() {
+    check(
+        vec![
+            Command(Mov),
+            Register(CYC),
+            Register(EX2),
+            Command(StoreEx),
+            Int32(HEAP),
+            Register(EX2),
+            Command(Add),
+            Int(1),
+            Int(2),
+            Command(Mul),
+            Int(3),
+            Int(4),
+            Command(Div),
+            Int(8),
+            Int(2),
+            Command(MemSet),
+            Int32(HEAP + 10),
+            Int(0),
+            Int(5),
+            Command(Mov),
+            Register(CYC),
+            Register(EX1),
+        ],
+        |m| {
+            //StoreEx 3, Add 1, Mul 4, Div 12, MemSet 4 + 5 words, the second Mov 1
+            assert_eq!(get_reg(10, &m.core) as i32 - read_i32(m, HEAP), 30);
+            assert!(m.core.cycles >= 30);
+        },
+    );
+}
+#[test]
+fn clock_throttles_to_target() {
+    let blocks = vec![
+        vec![Command(Mov), Int(0), Register(R4)],
+        vec![
+            Command(Add),
+            Register(R4),
+            Int(1),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(LessThan),
+            Register(R4),
+            Int(2_000),
+            Command(JumpNotZero),
+            BlockLoc(1),
+            Register(R1),
+            Command(Exit),
+        ],
+    ];
+    for backend in BACKENDS {
+        let mut m = load_exe(main_exe(blocks.clone()), backend);
+        //5 cycles a loop, ~10k cycles at 200kHz is ~50ms
+        m.clock_hz = Some(200_000);
+        m.run();
+        assert_eq!(m.fault, None);
+        assert!(m.core.cycles >= 10_000);
+        //the throttle's sleeps are idle time, and the guest never gets more than a throttle
+        //slice ahead of the clock
+        assert!(m.freq.2 > Duration::ZERO, "on {:?}", backend);
+        assert!(m.freq.2 <= m.freq.1.elapsed());
+        let guest = Duration::from_secs_f64(m.core.cycles as f64 / 200_000.0);
+        assert!(guest <= m.freq.1.elapsed() + Duration::from_millis(2));
+    }
+}
+#[test]
+fn wait_counts_as_idle_time() {
+    for backend in BACKENDS {
+        let code = vec![Command(Wait), Command(Wait), Command(Exit)];
+        let mut m = load_exe(main_exe(vec![code]), backend);
+        //1000 cycles a frame
+        m.clock_hz = Some(60_000);
+        m.run();
+        assert_eq!(m.fault, None);
+        //frames are 1/60s apart counting from machine start, the second Wait sleeps through one
+        assert!(m.freq.2 >= Duration::from_millis(16), "on {:?}", backend);
+        assert!(m.freq.2 <= m.freq.1.elapsed());
+        //and guest time moves on to the frame boundary however long the host slept
+        assert_eq!(m.core.cycles, 2000 + Exit.cycles(), "on {:?}", backend);
+    }
+}
+#[test]
+fn wait_parks_only_the_waiting_core() {
+    //the worker counts until core 0 is done waiting and exits
+    check_blocks(
+        vec![
+            vec![
+                Command(StartCore),
+                BlockLoc(1),
+                Int32(0),
+                Command(Wait),
+                Command(Load),
+                Int32(HEAP),
+                Register(R4),
+                Command(Exit),
+            ],
+            vec![
+                Command(FetchAdd),
+                Int32(HEAP),
+                Int(1),
+                Command(Jump),
+                BlockLoc(1),
+            ],
+        ],
+        |m| {
+            assert_eq!(m.core.id, 0);
+            assert!(m.core.r4 != 0, "the worker ran while core 0 waited");
+            assert_eq!(m.freq.2, Duration::ZERO, "the host never slept");
+        },
+    );
+}
+#[test]
+fn waiting_cores_wake_on_guest_time() {
+    //how far the worker got while core 0 waited only depends on cycles, not on the host
+    let count = |backend| {
+        let mut m = load_exe(
+            main_exe(vec![
+                vec![
+                    Command(StartCore),
+                    BlockLoc(1),
+                    Int32(0),
+                    Command(Wait),
+                    Command(Load),
+                    Int32(HEAP),
+                    Register(R4),
+                    Command(Exit),
+                ],
+                vec![Command(FetchAdd), Int32(HEAP), Int(1), Command(Jump), BlockLoc(1)],
+            ]),
+            backend,
+        );
+        //a frame is 10000 cycles, about 17ms
+        m.clock_hz = Some(600_000);
+        m.run();
+        assert_eq!(m.fault, None);
+        m.core.r4
+    };
+    let counts: Vec<i16> = [BACKENDS, BACKENDS].concat().into_iter().map(count).collect();
+    assert!(counts[0] > 0);
+    assert!(counts.iter().all(|r4| *r4 == counts[0]), "{:?}", counts);
+}
+
+//control flow
+#[test]
+fn jump() {
+    check_blocks(
+        vec![
+            vec![Command(Jump), BlockLoc(2)],
+            vec![Command(Mov), Int(1), Register(R4), Command(Exit)],
+            vec![Command(Mov), Int(2), Register(R4), Command(Exit)],
+        ],
+        |m| assert_eq!(m.core.r4, 2),
+    );
+}
+#[test]
+fn jump_zero() {
+    check_blocks(
+        vec![
+            vec![
+                Command(JumpZero),
+                BlockLoc(1),
+                Int(1),
+                Command(JumpZero),
+                BlockLoc(2),
+                Int(0),
+                Command(Exit),
+            ],
+            vec![Command(Mov), Int(1), Register(R4), Command(Exit)],
+            vec![Command(Mov), Int(2), Register(R4), Command(Exit)],
+        ],
+        |m| assert_eq!(m.core.r4, 2),
+    );
+}
+#[test]
+fn jump_not_zero() {
+    check_blocks(
+        vec![
+            vec![
+                Command(JumpNotZero),
+                BlockLoc(1),
+                Int(0),
+                Command(JumpNotZero),
+                BlockLoc(2),
+                Int(-1),
+                Command(Exit),
+            ],
+            vec![Command(Mov), Int(1), Register(R4), Command(Exit)],
+            vec![Command(Mov), Int(2), Register(R4), Command(Exit)],
+        ],
+        |m| assert_eq!(m.core.r4, 2),
+    );
+}
+#[test]
+fn loop_counts_down() {
+    check_blocks(
+        vec![
+            vec![Command(Mov), Int(100), Register(R4)],
+            vec![
+                Command(Add),
+                Register(R5),
+                Int(2),
+                Command(Mov),
+                Register(R1),
+                Register(R5),
+                Command(Sub),
+                Register(R4),
+                Int(1),
+                Command(Mov),
+                Register(R1),
+                Register(R4),
+                Command(JumpNotZero),
+                BlockLoc(1),
+                Register(R4),
+                Command(Exit),
+            ],
+        ],
+        |m| {
+            assert_eq!(m.core.r5, 200);
+            assert!(m.freq.0 > 500);
+        },
+    );
+}
+//the second pass runs from the icache's fast forms, which have to saturate like execute
+#[test]
+fn cached_loop_saturates_like_the_first_pass() {
+    check_blocks(
+        vec![
+            vec![Command(Mov), Int(0), Register(R3)],
+            vec![
+                Command(Add),
+                Int(30_000),
+                Int(30_000),
+                Command(Mov),
+                Register(R1),
+                Register(R2),
+                Command(Mul),
+                Register(R2),
+                Int(-2),
+                Command(Store),
+                Int32(HEAP),
+                Register(R1),
+                Command(Load),
+                Int32(HEAP),
+                Register(R4),
+                Command(Xor),
+                Int32(70_000),
+                Int(0),
+                Command(Mov),
+                Register(R1),
+                Register(R5),
+                Command(Mov),
+                Int32(-70_000),
+                Register(R2),
+                Command(Add),
+                Register(R3),
+                Int(1),
+                Command(Mov),
+                Register(R1),
+                Register(R3),
+                Command(LessThan),
+                Register(R3),
+                Int(3),
+                Command(JumpNotZero),
+                BlockLoc(1),
+                Register(R1),
+                Command(Exit),
+            ],
+        ],
+        |m| {
+            assert_eq!(m.core.r3, 3);
+            assert_eq!(m.core.r2, i16::MIN);
+            assert_eq!(m.core.r4, i16::MIN);
+            assert_eq!(read(m, HEAP), i16::MIN);
+            assert_eq!(m.core.r5, i16::MAX);
+        },
+    );
+}
+
+//[args][prev arp:i32 @ARP][return ip:i32 @ARP+2][symbols @ARP+4]
+fn frame_exe() -> Executable {
+    let mut exe = Executable::new();
+    let mut callee = Fn::new("callee".to_string(), 1);
+    callee.add_symbol("local", 1);
+    callee.add_block(
+        vec![
+            //argument
+            Command(AddEx),
+            Register(ARP),
+            Argument(0),
+            Command(Load),
+            Register(EX1),
+            Register(R4),
+            Command(Store),
+            Int32(HEAP),
+            Register(R4),
+            //saved ARP and return address
+            Command(LoadEx),
+            Register(ARP),
+            Register(EX2),
+            Command(StoreEx),
+            Int32(HEAP + 2),
+            Register(EX2),
+            Command(AddEx),
+            Register(ARP),
+            Int(2),
+            Command(LoadEx),
+            Register(EX1),
+            Register(EX2),
+            Command(StoreEx),
+            Int32(HEAP + 4),
+            Register(EX2),
+            Command(StoreEx),
+            Int32(HEAP + 6),
+            Register(ARP),
+            //locals start right after the saved words
+            Command(AddEx),
+            Bytecode::Symbol("local".to_string(), 0),
+            Register(ARP),
+            Command(Store),
+            Register(EX1),
+            Int(77),
+            Command(SubEx),
+            Register(EX1),
+            Register(ARP),
+            Command(StoreEx),
+            Int32(HEAP + 10),
+            Register(EX1),
+            //ex2 overlaps r4, reload the argument
+            Command(Load),
+            Int32(HEAP),
+            Register(R4),
+            Command(Mul),
+            Register(R4),
+            Int(2),
+            Command(Push),
+            Register(R1),
+            Command(Return),
+            Int(1),
+            SymbolSectionLen(),
+            ArgCount(),
+        ],
+        true,
+    );
+    exe.add_fn(callee);
+    let mut main_fn = Fn::new("main".to_string(), 0);
+    main_fn.add_block(
+        vec![
+            Command(Push),
+            Int(21),
+            Command(Call),
+            FunctionRef("callee".to_string()),
+            Command(StoreEx),
+            Int32(HEAP + 8),
+            Register(IP),
+            Command(Pop),
+            Register(R5),
+            Command(Exit),
+        ],
+        true,
+    );
+    exe.add_fn(main_fn);
+    exe
+}
+#[test]
+fn call_return_frame_layout() {
+    check_exe(frame_exe, |m| {
+        assert_eq!(read(m, HEAP), 21, "argument below ARP");
+        assert_eq!(read_i32(m, HEAP + 2) as usize, STACK_BASE, "caller's ARP");
+        //IP operands read one word past the command
+        assert_eq!(read_i32(m, HEAP + 4) + 1, read_i32(m, HEAP + 8), "return address");
+        assert_eq!(read_i32(m, HEAP + 6) as usize, STACK_BASE + 1, "ARP past the argument");
+        assert_eq!(read_i32(m, HEAP + 10), 4, "first local at ARP+4");
+        assert_eq!(m.core.r5, 42, "returned value");
+        assert_eq!(m.core.srp, 0);
+        assert_eq!(m.core.stack.len(), 0);
+        assert_eq!(m.core.arp, STACK_BASE);
+    });
+}
+#[test]
+fn enter_leave_ret() {
+    check_exe(
+        || {
+            let mut exe = Executable::new();
+            let mut divmod = Fn::new("divmod".to_string(), 2);
+            divmod.add_symbol("scratch", 2);
+            divmod.add_block(
+                vec![
+                    Command(Load),
+                    RegOffset(ARP, -2),
+                    Register(R4),
+                    Command(Load),
+                    RegOffset(ARP, -1),
+                    Register(R5),
+                    Command(Store),
+                    RegOffset(ARP, 4),
+                    Int(99),
+                    //temporaries Leave has to drop along with the locals
+                    Command(Push),
+                    Int(-1),
+                    Command(PushEx),
+                    Int32(-1),
+                    Command(Leave),
+                    Command(Store),
+                    Int32(HEAP),
+                    Register(SP),
+                    Command(Div),
+                    Register(R4),
+                    Register(R5),
+                    Command(Push),
+                    Register(R1),
+                    Command(Mod),
+                    Register(R4),
+                    Register(R5),
+                    Command(Push),
+                    Register(R1),
+                    Command(PushEx),
+                    Int32(70_000),
+                    Command(Ret),
+                    Int(4),
+                    ArgCount(),
+                ],
+                true,
+            );
+            exe.add_fn(divmod);
+            exe.add_fn(Fn::new_with_blocks(
+                "main".to_string(),
+                0,
+                vec![vec![
+                    Command(Push),
+                    Int(17),
+                    Command(Push),
+                    Int(5),
+                    Command(Call),
+                    FunctionRef("divmod".to_string()),
+                    Command(PopEx),
+                    Register(EX1),
+                    Command(Pop),
+                    Register(R4),
+                    Command(Pop),
+                    Register(R5),
+                    Command(Exit),
+                ]],
+            ));
+            exe
+        },
+        |m| {
+            assert_eq!(read(m, HEAP), 6, "args and frame header survive Leave");
+            assert_eq!(get_reg(10, &m.core), 70_000.0);
+            assert_eq!(m.core.r4, 2);
+            assert_eq!(m.core.r5, 3);
+            assert_eq!(m.core.srp, 0);
+            assert_eq!(m.core.stack.len(), 0);
+            assert_eq!(m.core.arp, STACK_BASE);
+        },
+    );
+}
+#[test]
+fn runaway_recursion_overflows() {
+    let faults = BACKENDS.map(|backend| {
+        let mut exe = Executable::new();
+        exe.add_fn(Fn::new_with_blocks(
+            "recurse".to_string(),
+            0,
+            vec![vec![Command(Call), FunctionRef("recurse".to_string())]],
+        ));
+        exe.add_fn(Fn::new_with_blocks(
+            "main".to_string(),
+            0,
+            vec![vec![Command(Call), FunctionRef("recurse".to_string()), Command(Exit)]],
+        ));
+        let m = run_exe(exe, backend);
+        //the Call that overflowed, not where it was headed. Bodies start after the 10 word
+        //prologue every fn gets.
+        let at = match m.fault {
+            Some(VmError::StackOverflow { ip, .. }) => m.symbols.resolve(ip),
+            _ => None,
+        };
+        assert_eq!(at, Some(("recurse", 10)), "on {:?}", backend);
+        m.fault
+    });
+    assert_eq!(faults[0], faults[1], "backends disagree");
+    match faults[0] {
+        //every frame is just the saved ARP and return address
+        Some(VmError::StackOverflow { depth, limit, .. }) => {
+            assert_eq!(limit, DEFAULT_STACK_LIMIT);
+            assert_eq!(depth, limit / 4);
+        }
+        ref fault => panic!("expected a stack overflow, got {:?}", fault),
+    }
+}
+#[test]
+fn pop_empty_stack_underflows() {
+    //the popping instruction's own IP, not the next one's, past main's prologue
+    for (code, at) in [
+        (vec![Command(Push), Int(1), Command(PopEx), Register(EX1)], 12),
+        (vec![Command(Pop), Register(R1)], 10),
+    ] {
+        for backend in BACKENDS {
+            let m = run_exe(main_exe(vec![code.clone()]), backend);
+            match m.fault {
+                Some(VmError::StackUnderflow { ip, depth: 0 }) => {
+                    assert_eq!(m.symbols.resolve(ip), Some(("main", at)), "on {:?}", backend)
+                }
+                ref fault => panic!("expected a stack underflow, got {:?}", fault),
+            }
+        }
+    }
+}
+#[test]
+fn fault_backtrace_names_frames() {
+    for backend in BACKENDS {
+        let mut exe = Executable::new();
+        exe.add_fn(Fn::new_with_blocks(
+            "inner".to_string(),
+            0,
+            vec![vec![
+                Command(Load),
+                Int32(STACK_BASE as i32 + 1_000),
+                Register(R1),
+            ]],
+        ));
+        exe.add_fn(Fn::new_with_blocks(
+            "outer".to_string(),
+            1,
+            vec![vec![Command(Call), FunctionRef("inner".to_string())]],
+        ));
+        exe.add_fn(Fn::new_with_blocks(
+            "main".to_string(),
+            0,
+            vec![vec![
+                Command(Push),
+                Int(1),
+                Command(Call),
+                FunctionRef("outer".to_string()),
+                Command(Exit),
+            ]],
+        ));
+        let m = run_exe(exe, backend);
+        assert!(matches!(m.fault, Some(VmError::Unmapped { .. })));
+        let names: Vec<&str> = m
+            .fault_trace
+            .iter()
+            .map(|frame| frame.func.as_ref().expect("frame outside any fn").0.as_str())
+            .collect();
+        assert_eq!(names, ["inner", "outer", "main"], "on {:?}", backend);
+        //embedders get the frames with the error
+        let report = m.fault_report().unwrap();
+        assert_eq!((&report.error, &report.trace), (m.fault.as_ref().unwrap(), &m.fault_trace));
+        let text = report.to_string();
+        assert!(text.starts_with("Unmapped read"), "{}", text);
+        assert!(text.contains("#1 ") && text.contains(" outer+"), "{}", text);
+    }
+}
+#[test]
+fn nested_calls_unwind() {
+    check_exe(
+        || {
+            let mut exe = Executable::new();
+            let mut count = Fn::new("count".to_string(), 1);
+            let base = count.add_block(
+                vec![Command(Push), Int(0), Command(Return), Int(1), SymbolSectionLen(), ArgCount()],
+                false,
+            );
+            count.add_block(
+                vec![
+                    Command(AddEx),
+                    Register(ARP),
+                    Argument(0),
+                    Command(Load),
+                    Register(EX1),
+                    Register(R1),
+                    Command(JumpZero),
+                    BlockLoc(base),
+                    Register(R1),
+                    Command(Sub),
+                    Register(R1),
+                    Int(1),
+                    Command(Push),
+                    Register(R1),
+                    Command(Call),
+                    FunctionRef("count".to_string()),
+                    Command(Pop),
+                    Register(R1),
+                    Command(Add),
+                    Register(R1),
+                    Int(1),
+                    Command(Push),
+                    Register(R1),
+                    Command(Return),
+                    Int(1),
+                    SymbolSectionLen(),
+                    ArgCount(),
+                ],
+                true,
+            );
+            exe.add_fn(count);
+            exe.add_fn(Fn::new_with_blocks(
+                "main".to_string(),
+                0,
+                vec![vec![
+                    Command(Push),
+                    Int(500),
+                    Command(Call),
+                    FunctionRef("count".to_string()),
+                    Command(Pop),
+                    Register(R5),
+                    Command(Exit),
+                ]],
+            ));
+            exe
+        },
+        |m| {
+            assert_eq!(m.core.r5, 500);
+            assert_eq!(m.core.stack.len(), 0);
+            assert_eq!(m.core.arp, STACK_BASE);
+        },
+    );
+}
+//main calls through a table of [double, negate]
+fn table_exe(entry: Vec<Bytecode>) -> Executable {
+    let mut exe = Executable::new();
+    let table = exe.add_constant(vec![
+        Data::FunctionRef("double".to_string()),
+        Data::FunctionRef("negate".to_string()),
+    ]);
+    for (name, op) in [("double", Mul), ("negate", Sub)] {
+        let operands = match op {
+            Mul => [Register(R4), Int(2)],
+            _ => [Int(0), Register(R4)],
+        };
+        let mut code = vec![Command(op)];
+        code.extend(operands);
+        code.extend([
+            Command(Push),
+            Register(R1),
+            Command(Return),
+            Int(1),
+            SymbolSectionLen(),
+            ArgCount(),
+        ]);
+        exe.add_fn(Fn::new_with_blocks(name.to_string(), 0, vec![code]));
+    }
+    let mut main_fn = Fn::new("main".to_string(), 0);
+    //ex1 points at the table
+    let mut code = vec![Command(AddEx), ConstantLoc(table), Int(0)];
+    code.extend(entry);
+    code.push(Command(Exit));
+    main_fn.add_block(code, true);
+    exe.add_fn(main_fn);
+    exe
+}
+#[test]
+fn call_indirect_through_memory() {
+    check_exe(
+        || {
+            table_exe(vec![
+                Command(Mov),
+                Int(7),
+                Register(R4),
+                Command(AddEx),
+                Register(EX1),
+                Int(2),
+                Command(CallIndirect),
+                Register(EX1),
+                Command(Pop),
+                Register(R4),
+                //callee prologues clobber ex1
+                Command(AddEx),
+                ConstantLoc(0),
+                Int(0),
+                Command(CallIndirect),
+                Register(EX1),
+                Command(Pop),
+                Register(R5),
+            ])
+        },
+        |m| {
+            assert_eq!((m.core.r4, m.core.r5), (-7, -14));
+            assert_eq!(m.core.stack.len(), 0);
+        },
+    );
+}
+#[test]
+fn call_through_register() {
+    //plain Call with a register operand is the register form
+    check_exe(
+        || {
+            table_exe(vec![
+                Command(LoadEx),
+                Register(EX1),
+                Register(EX1),
+                Command(Mov),
+                Int(4),
+                Register(R4),
+                Command(Call),
+                Register(EX1),
+                Command(Pop),
+                Register(R5),
+            ])
+        },
+        |m| assert_eq!(m.core.r5, 8),
+    );
+}
+#[test]
+fn jump_indirect() {
+    check_exe(
+        || {
+            let mut exe = table_exe(vec![
+                Command(AddEx),
+                Register(EX1),
+                Int(4),
+                Command(JumpIndirect),
+                Register(EX1),
+                Command(Mov),
+                Int(1),
+                Register(R5),
+            ]);
+            let done = exe.add_constant(vec![Data::FunctionRef("done".to_string())]);
+            assert_eq!(done, 1, "lands right after the call table");
+            exe.add_fn(Fn::new_with_blocks(
+                "done".to_string(),
+                0,
+                vec![vec![Command(Mov), Int(2), Register(R5), Command(Exit)]],
+            ));
+            exe
+        },
+        |m| assert_eq!(m.core.r5, 2),
+    );
+}
+//r5 = 10 * case, cases past the table land on the default block
+fn switch_exe(value: i16) -> Executable {
+    let mut exe = Executable::new();
+    let mut main_fn = Fn::new("main".to_string(), 0);
+    let cases = [1, 2, 3].map(|case| {
+        main_fn.add_block(
+            vec![Command(Mov), Int(10 * case), Register(R5), Command(Exit)],
+            false,
+        )
+    });
+    let default = main_fn.add_block(
+        vec![Command(Mov), Int(-1), Register(R5), Command(Exit)],
+        false,
+    );
+    let table = main_fn.add_jump_table(cases.to_vec());
+    main_fn.add_block(
+        vec![
+            Command(Switch),
+            Int(value),
+            JumpTable(table),
+            Int(3),
+            BlockLoc(default),
+        ],
+        true,
+    );
+    exe.add_fn(main_fn);
+    exe
+}
+#[test]
+fn switch() {
+    for (value, expected) in [(0, 10), (1, 20), (2, 30), (3, -1), (-1, -1)] {
+        check_exe(|| switch_exe(value), |m| assert_eq!(m.core.r5, expected));
+    }
+}
+
+//devices
+#[test]
+fn io_reads_the_clock() {
+    check(vec![Command(IO), Int(2), Int(0), Command(Popf), Register(F1)], |m| {
+        assert!(m.core.f1 > 0.0);
+        assert_eq!(m.core.stack.len(), 0);
+    });
+}
+#[test]
+fn perf_reads_a_counter() {
+    check(
+        vec![
+            Command(Push),
+            Int(3),
+            Command(IO),
+            Int(4),
+            Int(0),
+            Command(PopEx),
+            Register(EX1),
+            Command(IO),
+            Int(2),
+            Int(0),
+            Command(Popf),
+            Register(F1),
+            Command(Push),
+            Int(3),
+            Command(IO),
+            Int(4),
+            Int(0),
+            Command(PopEx),
+            Register(EX2),
+        ],
+        |m| {
+            let calls = get_reg(11, &m.core) - get_reg(10, &m.core);
+            assert_eq!(calls, 2.0, "clock and perf IO calls");
+            assert_eq!(m.core.stack.len(), 0);
+        },
+    );
+}
+#[test]
+fn perf_snapshot_writes_every_counter() {
+    check(
+        vec![
+            Command(Push),
+            Int(1),
+            Command(Push),
+            Int(2),
+            Command(Push),
+            Int(3),
+            Command(Pop),
+            Register(R2),
+            Command(Pop),
+            Register(R2),
+            Command(Pop),
+            Register(R2),
+            Command(PushEx),
+            Int32(HEAP),
+            Command(IO),
+            Int(4),
+            Int(1),
+        ],
+        |m| {
+            let instructions = read_i32(m, HEAP);
+            assert!(instructions >= 7);
+            assert!(read_i32(m, HEAP + 2) >= instructions, "cycles");
+            assert_eq!(read_i32(m, HEAP + 4), 0, "frames");
+            //the loader's IO counts too
+            assert_eq!(read_i32(m, HEAP + 6) as u64, m.perf.io_calls, "io calls");
+            assert_eq!(read_i32(m, HEAP + 8), 3, "stack high water");
+            assert_eq!(read_i32(m, HEAP + 10), 0, "frame time");
+        },
+    );
+}
+#[test]
+fn perf_snapshot_to_a_negative_dest_faults() {
+    let fault = expect_fault(vec![Command(PushEx), Int32(-2), Command(IO), Int(4), Int(1)]);
+    assert!(matches!(
+        fault,
+        VmError::Unmapped {
+            access: Access::Write,
+            ..
+        }
+    ));
+}
+#[test]
+fn query_device_reports_each_slot() {
+    check(
+        vec![
+            Command(QueryDevice),
+            Int(3),
+            Int32(HEAP),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(QueryDevice),
+            Int(1),
+            Int32(HEAP + 3),
+            Command(QueryDevice),
+            Int(9),
+            Int32(HEAP + 6),
+            Command(QueryDevice),
+            Int(-1),
+            Int32(HEAP + 9),
+        ],
+        |m| {
+            assert_eq!(m.core.r4, 5, "slots");
+            let words = |at| (0..3).map(|i| read(m, at + i)).collect::<Vec<i16>>();
+            //headless, so no window or speakers
+            assert_eq!(words(HEAP), vec![DeviceKind::Gfx as i16, 1, CAP_MMIO]);
+            assert_eq!(words(HEAP + 3), vec![DeviceKind::Audio as i16, 1, CAP_MMIO]);
+            assert_eq!(words(HEAP + 6), vec![0, 0, 0], "empty slot");
+            assert_eq!(words(HEAP + 9), vec![0, 0, 0], "negative slot");
+        },
+    );
+}
+#[test]
+fn io_to_a_missing_device_faults() {
+    let fault = expect_fault(vec![Command(IO), Int(7), Int(0)]);
+    assert!(matches!(fault, VmError::NoDevice { device: 7, .. }));
+    let fault = expect_fault(vec![Command(IO), Int(-1), Int(0)]);
+    assert!(matches!(fault, VmError::NoDevice { device: -1, .. }));
+}
+
+//multicore
+//main starts two workers on block 2 with arg loops, then waits until both bumped HEAP+1
+fn workers_exe(loops: i32, worker: Vec<Bytecode>) -> Executable {
+    let mut work = worker;
+    work.extend([
+        Command(Sub),
+        Register(R4),
+        Int(1),
+        Command(Mov),
+        Register(R1),
+        Register(R4),
+        Command(JumpNotZero),
+        BlockLoc(3),
+        Register(R1),
+        Command(FetchAdd),
+        Int32(HEAP + 1),
+        Int(1),
+        Command(Exit),
+    ]);
+    main_exe(vec![
+        vec![
+            Command(StartCore),
+            BlockLoc(2),
+            Int32(loops),
+            Command(Mov),
+            Register(R1),
+            Register(R3),
+            Command(StartCore),
+            BlockLoc(2),
+            Int32(loops),
+            Command(Mov),
+            Register(R1),
+            Register(R5),
+        ],
+        vec![
+            Command(Load),
+            Int32(HEAP + 1),
+            Register(R2),
+            Command(LessThan),
+            Register(R2),
+            Int(2),
+            Command(JumpNotZero),
+            BlockLoc(1),
+            Register(R1),
+            Command(Exit),
+        ],
+        vec![
+            Command(PopEx),
+            Register(EX2),
+            Command(Store),
+            RegOffset(CORE, HEAP + 2),
+            Register(CORE),
+        ],
+        work,
+    ])
+}
+#[test]
+fn cores_share_memory() {
+    check_exe(
+        || workers_exe(500, vec![Command(FetchAdd), Int32(HEAP), Int(1)]),
+        |m| {
+            assert_eq!((m.core.id, m.core.r3, m.core.r5), (0, 1, 2));
+            assert_eq!(read(m, HEAP), 1000);
+            assert_eq!((read(m, HEAP + 3), read(m, HEAP + 4)), (1, 2), "CORE");
+        },
+    );
+}
+#[test]
+fn cores_interleave_deterministically() {
+    //each worker logs its id at the next free slot, which shows the order cores ran in
+    let log = |backend| {
+        let worker = vec![
+            Command(FetchAdd),
+            Int32(HEAP),
+            Int(1),
+            Command(Store),
+            RegOffset(R1, HEAP + 10),
+            Register(CORE),
+        ];
+        let m = run_exe(workers_exe(200, worker), backend);
+        assert_eq!(m.fault, None);
+        (0..400).map(|i| read(&m, HEAP + 10 + i)).collect::<Vec<i16>>()
+    };
+    let [interpreted, threaded] = BACKENDS.map(log);
+    assert_eq!(interpreted, threaded);
+    assert_eq!(interpreted.iter().filter(|id| **id == 1).count(), 200);
+    assert_eq!(interpreted.iter().filter(|id| **id == 2).count(), 200);
+    //time slices are much shorter than a worker's run
+    assert!(interpreted.windows(2).filter(|w| w[0] != w[1]).count() >= 4);
+}
+#[test]
+fn cas() {
+    check(
+        vec![
+            Command(Store),
+            Int32(HEAP),
+            Int(5),
+            Command(Cas),
+            Int32(HEAP),
+            Int(5),
+            Int(7),
+            Command(Mov),
+            Register(R1),
+            Register(R4),
+            Command(Cas),
+            Int32(HEAP),
+            Int(5),
+            Int(9),
+        ],
+        |m| {
+            assert_eq!((m.core.r4, m.core.r1), (5, 7));
+            assert_eq!(read(m, HEAP), 7);
+        },
+    );
+}
+
+//coroutines
+#[test]
+fn coroutine_yields_values() {
+    check_blocks(
+        vec![
+            vec![
+                Command(CoCreate),
+                BlockLoc(1),
+                Int32(10),
+                Command(Mov),
+                Register(R1),
+                Register(R5),
+                Command(CoResume),
+                Register(R5),
+                Command(Mov),
+                Register(R1),
+                Register(R2),
+                Command(CoResume),
+                Register(R5),
+                Command(Mov),
+                Register(R1),
+                Register(R3),
+                Command(CoResume),
+                Register(R5),
+                Command(Mov),
+                Register(R1),
+                Register(R4),
+                Command(CoStatus),
+                Register(R5),
+                Command(Store),
+                Int32(HEAP),
+                Register(R1),
+                //runs to Exit, which ends the coroutine
+                Command(CoResume),
+                Register(R5),
+                Command(CoStatus),
+                Register(R5),
+                Command(Exit),
+            ],
+            vec![
+                Command(PopEx),
+                Register(EX1),
+                Command(CoYield),
+                Register(R2),
+                Command(Add),
+                Register(R2),
+                Int(10),
+                Command(CoYield),
+                Register(R1),
+                Command(Add),
+                Register(R2),
+                Int(20),
+                Command(CoYield),
+                Register(R1),
+                Command(Exit),
+            ],
+        ],
+        |m| {
+            assert_eq!((m.core.r2, m.core.r3, m.core.r4), (10, 20, 30));
+            assert_eq!(read(m, HEAP), 1, "suspended");
+            assert_eq!(m.core.r1, 0, "finished");
+            assert_eq!(m.core.coroutine, None);
+        },
+    );
+}
+#[test]
+fn coroutines_have_their_own_stack() {
+    check_blocks(
+        vec![
+            vec![
+                Command(Push),
+                Int(7),
+                Command(CoCreate),
+                BlockLoc(1),
+                Int32(0),
+                Command(CoResume),
+                Register(R1),
+                Command(Mov),
+                Register(R1),
+                Register(R4),
+                Command(Exit),
+            ],
+            vec![
+                Command(Push),
+                Int(5),
+                Command(Push),
+                Int(6),
+                Command(CoYield),
+                Register(SP),
+            ],
+        ],
+        |m| {
+            //the coroutine's arg and two pushes
+            assert_eq!(m.core.r4, 4);
+            assert_eq!(m.core.stack.len(), 1);
+        },
+    );
+}
+#[test]
+fn coroutines_get_the_machine_stack_limit() {
+    for backend in BACKENDS {
+        let machine = MachineBuilder::new().stack_limit(64).headless(true).build().unwrap();
+        let mut exe = main_exe(vec![
+            vec![
+                Command(CoCreate),
+                BlockLoc(1),
+                Int32(0),
+                Command(CoResume),
+                Register(R1),
+                Command(Exit),
+            ],
+            vec![Command(Call), FunctionRef("recurse".to_string())],
+        ]);
+        exe.add_fn(Fn::new_with_blocks(
+            "recurse".to_string(),
+            0,
+            vec![vec![Command(Call), FunctionRef("recurse".to_string())]],
+        ));
+        let mut m = load_exe_on(machine, exe, backend);
+        m.run();
+        assert!(m.core.coroutine.is_some(), "faulted inside the coroutine");
+        assert!(matches!(m.fault, Some(VmError::StackOverflow { limit: 64, .. })));
+    }
+}
+#[test]
+fn yield_outside_coroutine_faults() {
+    let fault = expect_fault(vec![Command(CoYield), Int(1)]);
+    assert!(matches!(fault, VmError::BadCoroutine { handle: None, .. }));
+    let fault = expect_fault(vec![Command(CoResume), Int(3)]);
+    assert!(matches!(fault, VmError::BadCoroutine { handle: Some(3), .. }));
+}
+
+//MMIO, each device's registers at MMIO + id * 256
+const MMIO: i32 = STACK_BASE as i32 - 4096;
+#[test]
+fn mmio_reads_device_registers() {
+    check(
+        vec![
+            Command(Loadf),
+            Int32(MMIO + 2 * 256),
+            Register(F1),
+            Command(LoadEx),
+            Int32(MMIO + 4 * 256),
+            Register(EX1),
+            //no device in slot 15
+            Command(Load),
+            Int32(MMIO + 15 * 256),
+            Register(R4),
+        ],
+        |m| {
+            assert!(m.core.f1 > 0.0, "clock");
+            assert!(get_reg(10, &m.core) > 0.0, "instructions retired");
+            assert_eq!(m.core.r4, 0);
+        },
+    );
+}
+#[test]
+fn mmio_writes_audio_registers() {
+    check(
+        vec![
+            Command(Storef),
+            Int32(MMIO + 256 + 2),
+            Float(440.0),
+            Command(Loadf),
+            Int32(MMIO + 256 + 2),
+            Register(F1),
+            Command(Store),
+            Int32(MMIO + 256 + 128),
+            Int(50),
+            Command(Load),
+            Int32(MMIO + 256 + 128),
+            Register(R4),
+        ],
+        |m| {
+            assert_eq!(m.core.f1, 440.0, "channel 0 frequency");
+            assert_eq!(m.core.r4, 50, "master volume");
+        },
+    );
+}
+#[test]
+fn mmio_scrolls_a_layer() {
+    check(
+        vec![
+            Command(Store),
+            Int32(HEAP + 2),
+            Int(-3),
+            Command(PushEx),
+            Int32(HEAP),
+            Command(IO),
+            Int(3),
+            Int(1),
+            Command(Store),
+            Int32(MMIO + 3 * 256 + 16),
+            Int(42),
+            Command(Load),
+            Int32(MMIO + 3 * 256 + 17),
+            Register(R4),
+        ],
+        |m| {
+            assert_eq!(read(m, HEAP + 1), 42, "x offset lands in the Layer struct");
+            assert_eq!(m.core.r4, -3);
+        },
+    );
+}
+
+//a device an embedder might plug in, IO id,0 adds the popped word to its count
+#[derive(Debug, Default)]
+struct Counter {
+    count: i16,
+    ticks: u64,
+}
+impl Device for Counter {
+    fn command(&mut self, machine: &mut Machine, _command: i16) {
+        self.count += machine.core.stack.pop(&mut machine.core.srp);
+    }
+    fn reset(&mut self) {
+        self.count = 0;
+    }
+    fn tick(&mut self, _machine: &mut Machine) {
+        self.ticks += 1;
+    }
+    fn mmio_read(&self, _machine: &Machine, _reg: usize) -> i16 {
+        self.count
+    }
+    fn mmio_write(&mut self, _machine: &mut Machine, _reg: usize, value: i16) {
+        self.count = value;
+    }
+    //rings while the count is positive
+    fn event(&mut self) -> bool {
+        self.count > 0
+    }
+}
+#[test]
+fn registered_device_takes_io_and_mmio() {
+    //the first free slot after the built in devices
+    let id = Machine::new_headless(false).devices.len();
+    for backend in BACKENDS {
+        let slot = MMIO + id as i32 * 256;
+        let code = vec![
+            Command(Push),
+            Int(5),
+            Command(IO),
+            Int(id as i16),
+            Int(0),
+            Command(Load),
+            Int32(slot),
+            Register(R4),
+            Command(Store),
+            Int32(slot),
+            Int(40),
+            Command(Push),
+            Int(2),
+            Command(IO),
+            Int(id as i16),
+            Int(0),
+            Command(Exit),
+        ];
+        let mut m = load_exe(main_exe(vec![code]), backend);
+        assert_eq!(m.register_device(Counter { count: 7, ticks: 0 }), id);
+        m.run();
+        assert_eq!(m.fault, None);
+        assert_eq!(m.core.r4, 5, "reset on boot, then counted");
+        assert_eq!(m.device::<Counter>(id).unwrap().count, 42);
+        assert!(m.device::<Counter>(0).is_none(), "slot 0 is the disk");
+        assert_eq!(m.devices[id].borrow().info().kind, CUSTOM_DEVICE);
+    }
+}
+#[test]
+fn devices_tick_with_guest_time() {
+    let mut m = load_exe(
+        main_exe(vec![
+            vec![Command(Mov), Int(0), Register(R2)],
+            vec![
+                Command(Add),
+                Register(R2),
+                Int(1),
+                Command(Mov),
+                Register(R1),
+                Register(R2),
+                Command(LessThan),
+                Register(R2),
+                Int(5000),
+                Command(JumpNotZero),
+                BlockLoc(1),
+                Register(R1),
+                Command(Exit),
+            ],
+        ]),
+        Backend::Threaded,
+    );
+    let id = m.register_device(Counter::default());
+    m.run();
+    let ticks = m.device::<Counter>(id).unwrap().ticks;
+    assert_eq!(ticks, m.core.cycles / DEVICE_TICK);
+}
+#[test]
+fn device_events_end_wait() {
+    let id = Machine::new_headless(false).devices.len() as i16;
+    let mut code = vec![Command(Push), Int(1), Command(IO), Int(id), Int(0)];
+    code.extend((0..10).map(|_| Command(Wait)));
+    code.push(Command(Exit));
+    for backend in BACKENDS {
+        let mut m = load_exe(main_exe(vec![code.clone()]), backend);
+        m.register_device(Counter::default());
+        m.run();
+        assert_eq!(m.fault, None);
+        assert_eq!(m.freq.2, Duration::ZERO, "none of the Waits slept on {:?}", backend);
+        //nor skipped ahead to a frame boundary
+        assert!(m.core.cycles < DEFAULT_CLOCK_HZ / 60, "on {:?}", backend);
+    }
+}
+#[test]
+fn host_fns_take_args_and_return_status() {
+    let slot = Machine::new_headless(false).devices.len() as i16;
+    let code = vec![
+        //add32(100000, 23)
+        Command(PushEx),
+        Int32(100_000),
+        Command(PushEx),
+        Int32(23),
+        Command(Push),
+        Int(4),
+        Command(Push),
+        Int(0),
+        Command(IO),
+        Int(slot),
+        Int(0),
+        Command(Pop),
+        Register(R1),
+        Command(Store),
+        Int32(HEAP),
+        Register(R1),
+        Command(PopEx),
+        Register(EX1),
+        Command(StoreEx),
+        Int32(HEAP + 1),
+        Register(EX1),
+        //fail()
+        Command(Push),
+        Int(0),
+        Command(Push),
+        Int(1),
+        Command(IO),
+        Int(slot),
+        Int(0),
+        Command(Pop),
+        Register(R4),
+        //no fn 9
+        Command(Push),
+        Int(0),
+        Command(Push),
+        Int(9),
+        Command(IO),
+        Int(slot),
+        Int(0),
+        Command(Pop),
+        Register(R5),
+        Command(Exit),
+    ];
+    for backend in BACKENDS {
+        let mut m = load_exe(main_exe(vec![code.clone()]), backend);
+        let add = m.register_host_fn("add32", |_, args| {
+            let sum = convert_i16_to_i32(&args[0..2]) + convert_i16_to_i32(&args[2..4]);
+            Ok(vec![DataType::Int32(sum)])
+        });
+        let fail = m.register_host_fn("fail", |_, _| Err(7));
+        assert_eq!((add, fail), (0, 1));
+        m.run();
+        assert_eq!(m.fault, None);
+        assert_eq!((read(&m, HEAP), read_i32(&m, HEAP + 1)), (HOST_OK, 100_023));
+        assert_eq!(m.core.r4, 7, "the fn's own status");
+        assert_eq!(m.core.r5, HOST_NO_FUNCTION);
+        assert_eq!(m.core.stack.len(), 0);
+        assert_eq!(m.devices[slot as usize].borrow().info().kind, DeviceKind::Host as i16);
+    }
+}
+
+//memory protection
+#[test]
+fn map_region_is_loader_only() {
+    let fault = expect_fault(vec![Command(MapRegion), Int32(HEAP), Int32(16), Int(3), Int(7)]);
+    assert!(matches!(fault, VmError::Privileged { .. }));
+}
+#[test]
+fn map_region_rejects_unknown_kinds() {
+    //a raw boot sector, so MapRegion runs in the loader
+    let boot = vec![pack_command(MapRegion), 300, 10, 9, 3, pack_command(Exit)];
+    for backend in BACKENDS {
+        let mut m = Machine::new_headless(false);
+        m.backend = backend;
+        m.set_disk(vec![DiskSection {
+            section_type: DiskSectionType::Entrypoint,
+            id: 0,
+            data: boot.clone(),
+        }]);
+        m.run();
+        assert_eq!(m.fault, Some(VmError::InvalidRegionKind { ip: 0, kind: 9 }));
+    }
+}
+#[test]
+fn later_regions_shadow_earlier_ones() {
+    let mut map = MemoryMap::boot(1 << 16);
+    assert!(map.allows(555, Access::Write));
+    map.map(Region::new(RegionKind::Code, 512..600, Perms::RX));
+    assert!(!map.allows(555, Access::Write), "mapping drops what allows remembered");
+    map.map(Region::new(RegionKind::Heap, 550..560, Perms::RW));
+    let kind = |addr| map.find(addr).map(|region| region.kind);
+    assert_eq!(kind(549), Some(RegionKind::Code));
+    assert_eq!(kind(555), Some(RegionKind::Heap));
+    assert_eq!(kind(560), Some(RegionKind::Code));
+    assert_eq!(kind(600), Some(RegionKind::Heap));
+    assert_eq!(kind(usize::MAX), None);
+    assert!(map.allows(555, Access::Write) && !map.allows(560, Access::Write));
+    assert_eq!(map.check_range(550..560, Access::Write), Ok(()));
+    let fault = map.check_range(555..565, Access::Write);
+    assert!(matches!(fault, Err(VmError::AccessFault { addr: 560, .. })), "{:?}", fault);
+}
+#[test]
+fn code_is_read_only() {
+    let fault = expect_fault(vec![Command(Mov), Register(IP), Register(EX1), Command(Store), Register(EX1), Int(0)]);
+    assert!(matches!(fault, VmError::AccessFault { .. }));
+}
+#[test]
+fn stack_past_top_is_unmapped() {
+    let fault = expect_fault(vec![Command(Load), Int32(STACK_BASE as i32 + 1), Register(R4)]);
+    assert!(matches!(fault, VmError::Unmapped { .. }));
+}
+
+//machine config
+#[test]
+fn builder_sets_memory_and_slots() {
+    let memory = 64 * 1024;
+    let mmio = memory as i32 - 4096;
+    for backend in BACKENDS {
+        let machine = MachineBuilder::new()
+            .memory(memory)
+            .devices(vec![DeviceKind::Disk, DeviceKind::Perf, DeviceKind::Clock])
+            .headless(true)
+            .build()
+            .unwrap();
+        let code = vec![
+            Command(LoadEx),
+            Int32(mmio + 256),
+            Register(EX1),
+            Command(Loadf),
+            Int32(mmio + 2 * 256),
+            Register(F1),
+            Command(Exit),
+        ];
+        let mut m = load_exe_on(machine, main_exe(vec![code]), backend);
+        m.run();
+        assert_eq!(m.fault, None);
+        assert_eq!(m.memory.stack_base(), memory);
+        assert!(get_reg(10, &m.core) > 0.0, "perf in slot 1");
+        assert!(m.core.f1 > 0.0, "clock in slot 2");
+    }
+}
+#[test]
+fn builder_sets_output_and_embedder_devices() {
+    let mut m = MachineBuilder::new()
+        .debug(false)
+        .devices(vec![DeviceKind::Disk, DeviceKind::Gfx, DeviceKind::Audio, DeviceKind::Host])
+        .resolution(160, 144)
+        .scale(2)
+        .sample_rate(22050)
+        .headless(true)
+        .build()
+        .unwrap();
+    assert_eq!(m.device::<AudioDevice>(2).unwrap().sample_rate, 22050);
+    assert!(m.devices[1].borrow().debug_dump().contains("width: 160, height: 144"));
+    //a host fn that drives an embedder's device, the guest then reads it over MMIO
+    let counter = m.register_device(Counter::default());
+    let bump = m.register_host_fn("bump", move |m, args| {
+        let mut device = m.device_mut::<Counter>(counter).unwrap();
+        device.count += args[0];
+        Ok(vec![DataType::Int(device.count)])
+    });
+    let code = vec![
+        Command(Push),
+        Int(21),
+        Command(Push),
+        Int(1),
+        Command(Push),
+        Int(bump as i16),
+        Command(IO),
+        Int(3),
+        Int(0),
+        Command(Pop),
+        Register(R4),
+        Command(Pop),
+        Register(R5),
+        Command(Load),
+        Int32(MMIO + counter as i32 * 256),
+        Register(R3),
+        Command(Exit),
+    ];
+    let mut m = load_exe_on(m, main_exe(vec![code]), Backend::Interpreter);
+    m.run();
+    assert_eq!(m.fault, None);
+    assert_eq!((m.core.r4, m.core.r5, m.core.r3), (HOST_OK, 21, 21));
+    assert_eq!(MachineBuilder::new().scale(3).build().err(), Some(ConfigError::Scale(3)));
+}
+#[test]
+fn config_from_toml() {
+    let config = MachineConfig::from_toml(
+        "memory = 65536\ndevices = [\"disk\", \"gfx\"]\nresolution = [160, 144]\nscale = 2\nheadless = true\n",
+    )
+    .unwrap();
+    assert_eq!(config.devices, vec![DeviceKind::Disk, DeviceKind::Gfx]);
+    assert_eq!((config.memory, config.resolution, config.scale), (65536, [160, 144], 2));
+    assert_eq!(config.sample_rate, MachineConfig::default().sample_rate);
+    assert_eq!(MachineConfig::from_toml(""), Ok(MachineConfig::default()));
+    assert_eq!(MachineConfig::from_toml("scale = 3"), Err(ConfigError::Scale(3)));
+    assert_eq!(MachineConfig::from_toml("memory = 16"), Err(ConfigError::Memory(16)));
+    assert_eq!(MachineConfig::from_toml("stack_limit = 0"), Err(ConfigError::StackLimit(0)));
+    assert_eq!(MachineConfig::from_toml("devices = [\"gfx\", \"disk\"]"), Err(ConfigError::DiskSlot));
+    let devices = format!("devices = [\"disk\"{}]", ", \"perf\"".repeat(16));
+    assert_eq!(MachineConfig::from_toml(&devices), Err(ConfigError::Devices(17)));
+    assert!(matches!(MachineConfig::from_toml("cpus = 2"), Err(ConfigError::Parse(_))));
+    assert!(matches!(MachineConfig::from_toml("devices = [\"tape\"]"), Err(ConfigError::Parse(_))));
+}
+#[test]
+fn host_calls_reject_bad_argc() {
+    let slot = Machine::new_headless(false).devices.len() as i16;
+    let call = |argc| {
+        vec![
+            Command(Push),
+            Int(argc),
+            Command(Push),
+            Int(0),
+            Command(IO),
+            Int(slot),
+            Int(0),
+        ]
+    };
+    let mut code = call(-1);
+    code.extend([Command(Pop), Register(R4)]);
+    code.extend(call(3));
+    code.extend([Command(Pop), Register(R5), Command(Exit)]);
+    for backend in BACKENDS {
+        let mut m = load_exe(main_exe(vec![code.clone()]), backend);
+        m.register_host_fn("nop", |_, _| Ok(vec![]));
+        m.run();
+        assert_eq!(m.fault, None);
+        assert_eq!((m.core.r4, m.core.r5), (HOST_BAD_ARGS, HOST_BAD_ARGS));
+        assert_eq!(m.core.stack.len(), 0);
+    }
+}
+#[test]
+fn host_fn_calling_its_own_device_faults() {
+    let slot = Machine::new_headless(false).devices.len();
+    let code = vec![
+        Command(Push),
+        Int(0),
+        Command(Push),
+        Int(0),
+        Command(IO),
+        Int(slot as i16),
+        Int(0),
+        Command(Exit),
+    ];
+    for backend in BACKENDS {
+        let mut m = load_exe(main_exe(vec![code.clone()]), backend);
+        m.register_host_fn("reenter", move |m, _| {
+            devices::command(m, m.core.ip, slot as i32, 0);
+            Ok(vec![])
+        });
+        m.run();
+        assert!(
+            matches!(m.fault, Some(VmError::DeviceBusy { device, .. }) if device == slot),
+            "got {:?} on {:?}",
+            m.fault,
+            backend
+        );
+    }
+}
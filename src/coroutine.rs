@@ -0,0 +1,103 @@
+use crate::error::{VmError, fault};
+use crate::vm::{Core, DataType, Machine};
+use std::mem;
+//live coroutines a machine can hold at once
+pub const MAX_COROUTINES: usize = 64;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoState {
+    //saved holds the coroutine's own registers and stack
+    Suspended,
+    //saved holds whoever resumed it, to be swapped back in on CoYield
+    Running,
+}
+#[derive(Debug)]
+pub struct Coroutine {
+    pub state: CoState,
+    saved: Core,
+}
+//Coroutines by handle. A coroutine is a whole saved Core, so it gets its own registers and
+//its own stack at STACK_BASE, resuming swaps it with the running context in Machine::core.
+#[derive(Debug)]
+pub struct Coroutines {
+    slots: Vec<Option<Coroutine>>,
+}
+impl Coroutines {
+    pub fn new() -> Coroutines {
+        Coroutines { slots: vec![] }
+    }
+    pub fn get(&self, handle: usize) -> Option<&Coroutine> {
+        self.slots.get(handle).and_then(|slot| slot.as_ref())
+    }
+}
+//A new suspended coroutine that starts at entry with arg on its stack, None when
+//MAX_COROUTINES are alive
+pub fn create(machine: &mut Machine, entry: usize, arg: i32) -> Option<usize> {
+    let mut saved = machine.new_core();
+    let slots = &mut machine.coroutines.slots;
+    let handle = match slots.iter().position(|slot| slot.is_none()) {
+        Some(handle) => handle,
+        None if slots.len() < MAX_COROUTINES => {
+            slots.push(None);
+            slots.len() - 1
+        }
+        None => return None,
+    };
+    saved.ip = entry;
+    saved.coroutine = Some(handle);
+    saved.stack.push(DataType::Int32(arg), &mut saved.srp);
+    slots[handle] = Some(Coroutine {
+        state: CoState::Suspended,
+        saved,
+    });
+    Some(handle)
+}
+pub fn resume(machine: &mut Machine, ip: usize, handle: usize) {
+    let co = match machine.coroutines.slots.get_mut(handle) {
+        Some(Some(co)) if co.state == CoState::Suspended => co,
+        _ => fault(VmError::BadCoroutine {
+            ip,
+            handle: Some(handle),
+        }),
+    };
+    co.state = CoState::Running;
+    switch(&mut machine.core, &mut co.saved);
+}
+//Hands value to the resumer in R1 and suspends the running coroutine
+pub fn yield_value(machine: &mut Machine, ip: usize, value: i16) {
+    let co = match machine.core.coroutine {
+        Some(handle) => machine.coroutines.slots[handle].as_mut().expect("running coroutine"),
+        None => fault(VmError::BadCoroutine { ip, handle: None }),
+    };
+    co.state = CoState::Suspended;
+    switch(&mut machine.core, &mut co.saved);
+    machine.core.r1 = value;
+}
+//Exit inside a coroutine, frees it and returns to the resumer with R1 = 0
+pub fn finish(machine: &mut Machine, handle: usize) {
+    let co = machine.coroutines.slots[handle].take().expect("running coroutine");
+    let mut resumer = co.saved;
+    switch(&mut machine.core, &mut resumer);
+    machine.core.r1 = 0;
+}
+//the core and the cycle counter stay, only the context changes
+fn switch(running: &mut Core, saved: &mut Core) {
+    saved.id = running.id;
+    saved.cycles = running.cycles;
+    mem::swap(running, saved);
+}
+pub fn dump_coroutines(machine: &Machine) {
+    println!("Coroutines:");
+    for (handle, slot) in machine.coroutines.slots.iter().enumerate() {
+        match slot {
+            Some(co) if co.state == CoState::Suspended => {
+                println!("  #{} suspended at %{}", handle, co.saved.ip)
+            }
+            Some(_) => println!("  #{} running", handle),
+            None => {}
+        }
+    }
+    match machine.core.coroutine {
+        Some(handle) => println!("Current: #{}", handle),
+        None => println!("Current: none"),
+    }
+}
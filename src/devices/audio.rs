@@ -1,6 +1,5 @@
 use crate::util::flatten_vec;
-use crate::util::pop_stack;
-use crate::util::unpack_float;
+use crate::util::{convert_float, unpack_float};
 use crate::vm::Machine;
 use arc_swap::{ArcSwap, ArcSwapAny};
 use hound;
@@ -14,103 +13,148 @@ use std::{
 };
 use tinyaudio::prelude::*;
 
-use super::RawDevice;
+use super::{CAP_MMIO, CAP_OUTPUT, Device, DeviceInfo, DeviceKind};
+//[registers]
+// channel c at 8c: 0..2 volume, 2..4 frequency, 4..6 left pan, 6..8 right pan, all f32
+// 128 master volume
+//An f32 register changes when its second word is written, Storef and StoreEx write the first
+//word before it.
+const CHANNEL_REGS: usize = 8;
+const MASTER_VOLUME_REG: usize = 128;
 //4 square, 2 triangle, 2 sawtooth, 2 sample
-pub fn driver(machine: &mut Machine, command: i16, device_id: usize) {
-    match command {
-        0 => {
-            //pause()
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                audio.pause();
-            }
-            if machine.debug {
-                println!("IO.audio.pause");
-            }
+impl Device for AudioDevice {
+    fn info(&self) -> DeviceInfo {
+        let output = if self.device.is_some() { CAP_OUTPUT } else { 0 };
+        DeviceInfo {
+            kind: DeviceKind::Audio as i16,
+            version: 1,
+            caps: CAP_MMIO | output,
+        }
+    }
+    fn cycles(&self, command: i16) -> u64 {
+        match command {
+            6 => 32,
+            _ => 8,
         }
-        1 => {
-            //unpause()
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                audio.unpause();
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        match command {
+            0 => {
+                //pause()
+                self.pause();
+                if machine.debug {
+                    println!("IO.audio.pause");
+                }
             }
-            if machine.debug {
-                println!("IO.audio.unpause");
+            1 => {
+                //unpause()
+                self.unpause();
+                if machine.debug {
+                    println!("IO.audio.unpause");
+                }
             }
-        }
-        2 => {
-            //changeVolume(channel,newVolume)
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                let args = pop_stack(&mut machine.core, 2);
-                let channel = args[0] as usize;
-                let new_volume = args[1] as f32;
-                audio.update_channel(channel, ChannelUpdate::Volume(new_volume));
+            2 => {
+                //changeVolume(channel: i16, newVolume: f32)
+                let channel = machine.core.stack.pop(&mut machine.core.srp) as usize;
+                let new_volume = machine.core.stack.pop_f32(&mut machine.core.srp);
+                self.update_channel(channel, ChannelUpdate::Volume(new_volume));
                 if machine.debug {
                     println!("IO.audio.changeVolume {} {}", channel, new_volume);
                 }
             }
-        }
-        3 => {
-            //changePan(channel,newPan)
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                let args = pop_stack(&mut machine.core, 3);
-                let channel = args[0] as usize;
-                let new_pan = [args[1] as f32, args[2] as f32];
-                audio.update_channel(channel, ChannelUpdate::Pan(new_pan));
+            3 => {
+                //changePan(channel: i16, left: f32, right: f32)
+                let channel = machine.core.stack.pop(&mut machine.core.srp) as usize;
+                let new_pan = [
+                    machine.core.stack.pop_f32(&mut machine.core.srp),
+                    machine.core.stack.pop_f32(&mut machine.core.srp),
+                ];
+                self.update_channel(channel, ChannelUpdate::Pan(new_pan));
                 if machine.debug {
                     println!(
                         "IO.audio.changePan {} [L: {}, R: {}]",
-                        channel, args[1], args[2]
+                        channel, new_pan[0], new_pan[1]
                     );
                 }
             }
-        }
-        4 => {
-            //changeFrequency(channel,newFrequency)
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                let args = pop_stack(&mut machine.core, 2);
-                let channel = args[0] as usize;
-                let new_frequency = args[1] as f32;
-                audio.update_channel(channel, ChannelUpdate::Frequency(new_frequency));
+            4 => {
+                //changeFrequency(channel: i16, newFrequency: f32)
+                let channel = machine.core.stack.pop(&mut machine.core.srp) as usize;
+                let new_frequency = machine.core.stack.pop_f32(&mut machine.core.srp);
+                self.update_channel(channel, ChannelUpdate::Frequency(new_frequency));
                 if machine.debug {
                     println!("IO.audio.changeFrequency {} {}", channel, new_frequency);
                 }
             }
-        }
-        5 => {
-            //changeMasterVolume(newVolume)
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                let args = pop_stack(&mut machine.core, 1);
-                let new_volume = args[0] as i32;
-                audio.set_master_volume(new_volume);
+            5 => {
+                //changeMasterVolume(newVolume: i16)
+                let new_volume = machine.core.stack.pop(&mut machine.core.srp) as i32;
+                self.set_master_volume(new_volume);
                 if machine.debug {
                     println!("IO.audio.changeMasterVolume {}", new_volume);
                 }
             }
-        }
-        6 => {
-            //loadSound(channel, ptr, len)
-            let args = pop_stack(&mut machine.core, 3);
-            let channel = args[0] as usize;
-            let ptr = args[1] as usize;
-            let len = args[2] as usize;
-            //dbg!(ptr, len, channel);
-            let rdata = machine.memory.read_range(ptr..ptr + len, machine).to_vec();
-            let data: Vec<f32> = rdata
-                .iter()
-                .enumerate()
-                .step_by(2)
-                .map(|(i, x)| {
-                    unpack_float(&[*x, rdata[i + 1]]).expect("Couldn't parse sample float")
-                })
-                .collect();
-            //println!("{:?}", rdata);
-            if let RawDevice::Audio(audio) = &mut machine.devices[device_id].contents {
-                audio.update_channel(channel, ChannelUpdate::WaveSample(data));
+            6 => {
+                //loadSound(channel: i16, ptr: i32, len: i32)
+                let channel = machine.core.stack.pop(&mut machine.core.srp) as usize;
+                let ptr = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                let len = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                //dbg!(ptr, len, channel);
+                let rdata = machine.memory.read_range(ptr..ptr + len, machine).to_vec();
+                let data: Vec<f32> = rdata
+                    .iter()
+                    .enumerate()
+                    .step_by(2)
+                    .map(|(i, x)| {
+                        unpack_float(&[*x, rdata[i + 1]]).expect("Couldn't parse sample float")
+                    })
+                    .collect();
+                //println!("{:?}", rdata);
+                self.update_channel(channel, ChannelUpdate::WaveSample(data));
                 if machine.debug {
                     println!("IO.audio.loadSound {} %[{}..{}]", channel, ptr, ptr + len);
                 }
             }
+            _ => {}
+        }
+    }
+    fn reset(&mut self) {
+        for (channel, fresh) in self.channels.iter().zip(default_channels()) {
+            channel.store(Arc::new(fresh));
+        }
+        self.master_volume.store(100, Relaxed);
+        self.old_vol = 1.0;
+    }
+    //every register, in MMIO order
+    fn snapshot(&self) -> Vec<i16> {
+        let mut words: Vec<i16> = (0..self.channels.len() * CHANNEL_REGS)
+            .map(|reg| self.register(reg))
+            .collect();
+        words.push(self.register(MASTER_VOLUME_REG));
+        words
+    }
+    fn mmio_read(&self, _machine: &Machine, reg: usize) -> i16 {
+        self.register(reg)
+    }
+    fn mmio_write(&mut self, _machine: &mut Machine, reg: usize, value: i16) {
+        let channel = reg / CHANNEL_REGS;
+        if reg == MASTER_VOLUME_REG {
+            self.set_master_volume(value as i32);
+        } else if reg.is_multiple_of(2) {
+            self.mmio_low = value;
+        } else if let Some(current) = self.channels.get(channel) {
+            let value = unpack_float(&[self.mmio_low, value]).expect("two words");
+            let update = match reg % CHANNEL_REGS / 2 {
+                0 => ChannelUpdate::Volume(value),
+                1 => ChannelUpdate::Frequency(value),
+                side => {
+                    let mut pan = current.load().pan;
+                    pan[side - 2] = value;
+                    ChannelUpdate::Pan(pan)
+                }
+            };
+            self.update_channel(channel, update);
         }
-        _ => {}
     }
 }
 pub struct AudioDevice {
@@ -119,6 +163,8 @@ pub struct AudioDevice {
     old_vol: f32,
     master_volume: Arc<AtomicI32>,
     device: Option<OutputDevice>,
+    //first word of an f32 register write, see mmio_write
+    mmio_low: i16,
 }
 impl std::fmt::Debug for AudioDevice {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -131,25 +177,38 @@ impl std::fmt::Debug for AudioDevice {
     }
 }
 impl AudioDevice {
-    pub fn new() -> AudioDevice {
+    //a headless device keeps channel state but never opens an output device
+    pub fn new(sample_rate: u32, headless: bool) -> AudioDevice {
         let mut a = AudioDevice {
-            channels: mutex_channels(flatten_vec(vec![
-                gen_uninitialized_channels(gen_square_wave as WaveGenerator, 4, 10.0),
-                gen_uninitialized_channels(gen_triangle_wave as WaveGenerator, 2, 10.0),
-                gen_uninitialized_channels(gen_sawtooth_wave as WaveGenerator, 2, 10.0),
-                vec![
-                    Channel::new_with_sample(vec![0.0], 0.1, [1.0, 1.0]),
-                    Channel::new_with_sample(vec![0.0], 0.1, [1.0, 1.0]),
-                ],
-            ])),
-            sample_rate: 32000,
+            channels: mutex_channels(default_channels()),
+            sample_rate,
             old_vol: 1.0,
             master_volume: Arc::new(AtomicI32::new(100)),
             device: None,
+            mmio_low: 0,
         };
-        a.run();
+        if !headless {
+            a.run();
+        }
         a
     }
+    fn register(&self, reg: usize) -> i16 {
+        if reg == MASTER_VOLUME_REG {
+            return self.master_volume.load(Relaxed) as i16;
+        }
+        match self.channels.get(reg / CHANNEL_REGS) {
+            Some(channel) => {
+                let channel = channel.load();
+                let value = match reg % CHANNEL_REGS / 2 {
+                    0 => channel.volume * 10.0,
+                    1 => channel.freq.unwrap_or(0.0),
+                    side => channel.pan[side - 2],
+                };
+                convert_float(value)[reg % 2]
+            }
+            None => 0,
+        }
+    }
     pub fn update_channel(&self, id: usize, update: ChannelUpdate) {
         modify_channel_collection_item(id, &(self.channels), update);
     }
@@ -184,6 +243,17 @@ impl AudioDevice {
             .store((self.old_vol * 100.0) as i32, Relaxed);
     }
 }
+fn default_channels() -> Vec<Channel> {
+    flatten_vec(vec![
+        gen_uninitialized_channels(gen_square_wave as WaveGenerator, 4, 10.0),
+        gen_uninitialized_channels(gen_triangle_wave as WaveGenerator, 2, 10.0),
+        gen_uninitialized_channels(gen_sawtooth_wave as WaveGenerator, 2, 10.0),
+        vec![
+            Channel::new_with_sample(vec![0.0], 0.1, [1.0, 1.0]),
+            Channel::new_with_sample(vec![0.0], 0.1, [1.0, 1.0]),
+        ],
+    ])
+}
 fn gen_uninitialized_channels(wave: WaveGenerator, count: i32, ttl: f32) -> Vec<Channel> {
     let mut r = vec![];
     for _i in 0..count {
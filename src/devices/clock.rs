@@ -1,7 +1,8 @@
-use crate::devices::RawDevice;
+use crate::devices::{CAP_MMIO, Device, DeviceInfo, DeviceKind};
+use crate::util::convert_float;
 use crate::vm::{DataType, Machine};
 use std::time::{SystemTime, UNIX_EPOCH};
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Clock {}
 
 impl Clock {
@@ -15,24 +16,32 @@ impl Clock {
             .as_secs_f32()
     }
 }
-pub fn driver(machine: &mut Machine, command: i16, device_id: usize) {
-    match command {
-        0 => {
-            if let RawDevice::Clock(clock) = &machine.devices[device_id].contents {
-                machine
-                    .core
-                    .stack
-                    .push(DataType::Float(clock.read()), &mut machine.core.srp);
-                if machine.debug {
-                    println!("IO.clock.read");
-                }
-            } else {
-                machine
-                    .core
-                    .stack
-                    .push(DataType::Float(0.0), &mut machine.core.srp);
+impl Device for Clock {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            kind: DeviceKind::Clock as i16,
+            version: 1,
+            caps: CAP_MMIO,
+        }
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        //read() -> f32
+        if command == 0 {
+            machine
+                .core
+                .stack
+                .push(DataType::Float(self.read()), &mut machine.core.srp);
+            if machine.debug {
+                println!("IO.clock.read");
             }
         }
-        _ => {}
+    }
+    //[registers]
+    // 0..2 f32 seconds since the epoch, what read() pushes
+    fn mmio_read(&self, _machine: &Machine, reg: usize) -> i16 {
+        match reg {
+            0 | 1 => convert_float(self.read())[reg],
+            _ => 0,
+        }
     }
 }
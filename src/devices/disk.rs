@@ -1,4 +1,4 @@
-use crate::devices::RawDevice;
+use crate::devices::{Device, DeviceInfo, DeviceKind};
 use crate::util::pop_stack;
 use crate::vm::Machine;
 pub type Disk = Vec<DiskSection>;
@@ -14,63 +14,94 @@ pub enum DiskSectionType {
     Libary,
     Code,
     Data,
+    //fn names and addresses for backtraces, never loaded into memory
+    Symbols,
 }
-pub fn driver(machine: &mut Machine, command: i16, device_id: usize) {
-    let disk = if let RawDevice::Disk(disk) = &mut machine.devices[device_id].contents {
-        Some(disk)
-    } else {
-        None
+//The drive in slot 0, the loader boots from the first section of its disk
+#[derive(Debug)]
+pub struct DiskDrive {
+    pub disk: Disk,
+}
+impl DiskDrive {
+    pub fn new(disk: Disk) -> DiskDrive {
+        DiskDrive { disk }
     }
-    .expect("Could not get disk");
-    match command as i16 {
-        0 => {
-            //read(section,addr,len,dest)
-            let cargs = pop_stack(&mut machine.core, 4);
-            for i in (cargs[1] as usize)..(cargs[1] + cargs[2]) as usize {
-                machine.memory.write(
-                    cargs[3] as usize + (i - cargs[1] as usize),
-                    disk[cargs[0] as usize].data[i],
-                    &mut machine.core,
-                );
-            }
-            if machine.debug {
-                println!(
-                    "IO.disk.read disk.%[{} {}] {} ->%{}",
-                    cargs[0], cargs[1], cargs[2], cargs[3]
-                );
-            }
+}
+impl Device for DiskDrive {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            kind: DeviceKind::Disk as i16,
+            version: 1,
+            caps: 0,
         }
-        1 => {
-            //write(section,addr,byte)
-            let cargs = pop_stack(&mut machine.core, 3);
-            disk[cargs[0] as usize].data[cargs[1] as usize] = cargs[2] as i16;
-            if machine.debug {
-                println!(
-                    "IO.disk.write {} -> disk.%[{} {}]",
-                    cargs[2], cargs[0], cargs[1]
-                );
-            }
+    }
+    //flat costs, transfers aren't charged per word
+    fn cycles(&self, command: i16) -> u64 {
+        match command {
+            0 => 64,
+            1 => 8,
+            2 => 256,
+            _ => 4,
         }
-        2 => {
-            //loadSectors(start,count,dest)
-            let cargs = pop_stack(&mut machine.core, 3)
-                .iter()
-                .map(|i| *i as usize)
-                .collect::<Vec<usize>>();
-            let mut next_mem = cargs[2];
-            for i in cargs[0]..cargs[0] + cargs[1] {
-                for (_j, byte) in disk[i].data.iter().enumerate() {
-                    machine.memory.write(next_mem, *byte, &mut machine.core);
-                    next_mem += 1;
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        let disk = &mut self.disk;
+        match command {
+            0 => {
+                //read(section,addr,len,dest)
+                let cargs = pop_stack(&mut machine.core, 4);
+                for i in (cargs[1] as usize)..(cargs[1] + cargs[2]) as usize {
+                    machine.memory.write(
+                        cargs[3] as usize + (i - cargs[1] as usize),
+                        disk[cargs[0] as usize].data[i],
+                        &mut machine.core,
+                    );
+                }
+                if machine.debug {
+                    println!(
+                        "IO.disk.read disk.%[{} {}] {} ->%{}",
+                        cargs[0], cargs[1], cargs[2], cargs[3]
+                    );
+                }
+            }
+            1 => {
+                //write(section,addr,byte)
+                let cargs = pop_stack(&mut machine.core, 3);
+                disk[cargs[0] as usize].data[cargs[1] as usize] = cargs[2] as i16;
+                if machine.debug {
+                    println!(
+                        "IO.disk.write {} -> disk.%[{} {}]",
+                        cargs[2], cargs[0], cargs[1]
+                    );
                 }
             }
-            if machine.debug {
-                println!(
-                    "IO.disk.loadSectors disk.%[{}] {} ->%{}",
-                    cargs[0], cargs[1], cargs[2]
-                );
+            2 => {
+                //loadSectors(start,count,dest)
+                let cargs = pop_stack(&mut machine.core, 3)
+                    .iter()
+                    .map(|i| *i as usize)
+                    .collect::<Vec<usize>>();
+                let mut next_mem = cargs[2];
+                for i in cargs[0]..cargs[0] + cargs[1] {
+                    for (_j, byte) in disk[i].data.iter().enumerate() {
+                        machine.memory.write(next_mem, *byte, &mut machine.core);
+                        next_mem += 1;
+                    }
+                }
+                if machine.debug {
+                    println!(
+                        "IO.disk.loadSectors disk.%[{}] {} ->%{}",
+                        cargs[0], cargs[1], cargs[2]
+                    );
+                }
             }
+            _ => {}
         }
-        _ => {}
+    }
+    fn debug_dump(&self) -> String {
+        self.disk
+            .iter()
+            .map(|s| format!("{:?} #{}: {} words\n", s.section_type, s.id, s.data.len()))
+            .collect()
     }
 }
@@ -1,201 +1,212 @@
-use crate::util::convert_i16_to_u32;
-use crate::vm::{Machine, unpack_dt};
-use crate::{devices::RawDevice, util::unpack_float};
+use crate::devices::{CAP_INPUT, CAP_MMIO, CAP_OUTPUT, Device, DeviceInfo, DeviceKind};
+use crate::util::{convert_i16_to_u32, convert_u32_to_i16, unpack_float};
+use crate::vm::Machine;
 use minifb::{self, Key, Scale, Window, WindowOptions};
 use std::{cell::RefCell, rc::Rc, vec};
-pub fn driver(machine: &mut Machine, command: i16, device_id: usize) {
-    //Types
-    //struct Atlas{
-    //  i16 len
-    //  [u32*64; len] tiles
-    //}
-    //struct Tilemap{
-    //  i16 tilemap_height
-    //  i16 tilemap_width
-    //  &[i16] tilemap
-    //}
-    //struct Sprite{
-    //  i16 id
-    //  i16 x
-    //  i16 y
-    //  u8 priority
-    //  Tilemap tilemap
-    //}
-    //enum LayerTransform{
-    //  Regular=>0,
-    //  SingleMatrixAffine=>1,
-    //  MultiMatrixAffine=>2
-    //}
-    //type Matrix:([f32;4],Point);
-    //type Point:[i16;2]
-    //struct Layer{
-    //  i16 id
-    //  i16 xOffset
-    //  i16 yOffset
-    //  Tilemap tilemap
-    //  LayerTransform transform
-    //  enum(&Matrix,&[Matrix],NULL) transformData
-    //}
-    //type NULL:u32=&0
-    //type Controls: [bool]=[A,B,X,Y,Left,Right,Up,Down,Start,LTrigger,RTrigger]
-    match command {
-        0 => {
-            //registerAtlas(&Atlas)
-            // Sets the ptr to the atlas of the graphics system
-            let ptr = unpack_dt(machine.core.stack.pop(&mut machine.core.srp)) as usize;
-            let gs: &mut GraphicsSystem =
-                (if let RawDevice::Graphics(gs) = &mut machine.devices[device_id].contents {
-                    Some(gs)
-                } else {
-                    None
-                })
-                .expect("Couldn't get graphics system");
-            gs.ptrs.atlas = ptr;
-            if machine.debug {
-                println!("IO.gfx.registerAtlas %{}", ptr);
-            }
+//[registers]
+// 0..11 controls as of the last render, 1 while held, in pullControls' order
+// 16 + 2n, 17 + 2n x and y offset of the nth registered layer, kept in its Layer struct
+const CONTROLS: usize = 11;
+const SCROLL_REGS: usize = 16;
+impl Device for GraphicsSystem {
+    fn info(&self) -> DeviceInfo {
+        let window = if self.display.window.is_some() {
+            CAP_OUTPUT | CAP_INPUT
+        } else {
+            0
+        };
+        DeviceInfo {
+            kind: DeviceKind::Gfx as i16,
+            version: 1,
+            caps: CAP_MMIO | window,
         }
-        1 => {
-            //registerLayerPtr(&Layer)
-            //Sets the ptr to a layer
-            let ptr = unpack_dt(machine.core.stack.pop(&mut machine.core.srp)) as usize;
-            let gs: &mut GraphicsSystem =
-                (if let RawDevice::Graphics(gs) = &mut machine.devices[device_id].contents {
-                    Some(gs)
-                } else {
-                    None
-                })
-                .expect("Couldn't get graphics system");
-            if !gs.ptrs.layers.contains(&ptr) {
-                gs.ptrs.layers.push(ptr);
-            }
-            if machine.debug {
-                println!("IO.gfx.registerLayer %{}", ptr);
-            }
+    }
+    //the CPU only pays for handing work to the graphics system
+    fn cycles(&self, command: i16) -> u64 {
+        match command {
+            3 => 512,
+            4 => 32,
+            _ => 16,
         }
-        2 => {
-            //registerSprite(&Sprite)
-            //Adds a sprite to be rendered
-            let ptr = unpack_dt(machine.core.stack.pop(&mut machine.core.srp)) as usize;
-            let gs: &mut GraphicsSystem =
-                (if let RawDevice::Graphics(gs) = &mut machine.devices[device_id].contents {
-                    Some(gs)
-                } else {
-                    None
-                })
-                .expect("Couldn't get graphics system");
-            if !gs.ptrs.sprites.contains(&ptr) {
-                gs.ptrs.sprites.push(ptr);
-            }
-            if machine.debug {
-                println!("IO.gfx.registerSprite %{}", ptr);
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        //Types
+        //struct Atlas{
+        //  i16 len
+        //  [u32*64; len] tiles
+        //}
+        //struct Tilemap{
+        //  i16 tilemap_height
+        //  i16 tilemap_width
+        //  &[i16] tilemap
+        //}
+        //struct Sprite{
+        //  i16 id
+        //  i16 x
+        //  i16 y
+        //  u8 priority
+        //  Tilemap tilemap
+        //}
+        //enum LayerTransform{
+        //  Regular=>0,
+        //  SingleMatrixAffine=>1,
+        //  MultiMatrixAffine=>2
+        //}
+        //type Matrix:([f32;4],Point);
+        //type Point:[i16;2]
+        //struct Layer{
+        //  i16 id
+        //  i16 xOffset
+        //  i16 yOffset
+        //  Tilemap tilemap
+        //  LayerTransform transform
+        //  enum(&Matrix,&[Matrix],NULL) transformData
+        //}
+        //type NULL:u32=&0
+        //type Controls: [bool]=[A,B,X,Y,Left,Right,Up,Down,Start,LTrigger,RTrigger]
+        //Pointer arguments are i32s (PushEx)
+        match command {
+            0 => {
+                //registerAtlas(&Atlas)
+                // Sets the ptr to the atlas of the graphics system
+                let ptr = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                self.ptrs.atlas = ptr;
+                if machine.debug {
+                    println!("IO.gfx.registerAtlas %{}", ptr);
+                }
             }
-        }
-        3 => {
-            //render()
-            //render layers & sprites
-            let (atlas_ptr, sprite_ptrs, layer_ptrs, scanlines) = {
-                let gs = get_gs(machine, device_id);
-                (
-                    gs.ptrs.atlas,
-                    &gs.ptrs.sprites,
-                    &gs.ptrs.layers,
-                    gs.display.height,
-                )
-            };
-            //borrow checker pleasing dance
-            let spl = sprite_ptrs.len();
-            let lpl = layer_ptrs.len();
-            load_atlas(atlas_ptr, machine, device_id);
-            for spc in 0..spl {
-                let sp = get_gs(machine, device_id).ptrs.sprites[spc];
-                load_sprite(sp, machine, device_id);
+            1 => {
+                //registerLayerPtr(&Layer)
+                //Sets the ptr to a layer
+                let ptr = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                if !self.ptrs.layers.contains(&ptr) {
+                    self.ptrs.layers.push(ptr);
+                }
+                if machine.debug {
+                    println!("IO.gfx.registerLayer %{}", ptr);
+                }
             }
-            for lpc in 0..lpl {
-                let lp = get_gs(machine, device_id).ptrs.layers[lpc];
-                load_layer(lp, machine, device_id, scanlines);
+            2 => {
+                //registerSprite(&Sprite)
+                //Adds a sprite to be rendered
+                let ptr = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                if !self.ptrs.sprites.contains(&ptr) {
+                    self.ptrs.sprites.push(ptr);
+                }
+                if machine.debug {
+                    println!("IO.gfx.registerSprite %{}", ptr);
+                }
             }
+            3 => {
+                //render()
+                //render layers & sprites
+                let scanlines = self.display.height;
+                load_atlas(self.ptrs.atlas, machine, self);
+                for sp in self.ptrs.sprites.clone() {
+                    load_sprite(sp, machine, self);
+                }
+                for lp in self.ptrs.layers.clone() {
+                    load_layer(lp, machine, self, scanlines);
+                }
 
-            get_gs(machine, device_id).render();
-            if !get_gs(machine, device_id).display.is_open() {
-                machine.on = false;
-            }
-            if machine.debug {
-                println!("IO.gfx.render");
+                self.render();
+                machine.perf.frame_rendered();
+                if !self.display.is_open() {
+                    machine.on = false;
+                }
+                if machine.debug {
+                    println!("IO.gfx.render");
+                }
             }
-        }
-        4 => {
-            //pullControls(writeLoc)->Controls
-            //writes the currently pressed controls to ptr, in (A,B,X,Y,Left,Right,Up,Down,Start,LTrigger,RTrigger) order.
-            let ptr = unpack_dt(machine.core.stack.pop(&mut machine.core.srp)) as usize;
-            let gs: &mut GraphicsSystem =
-                (if let RawDevice::Graphics(gs) = &mut machine.devices[device_id].contents {
-                    Some(gs)
-                } else {
-                    None
-                })
-                .expect("Couldn't get graphics system");
-            let rkeys = gs
-                .display
-                .pull_keys()
-                .iter()
-                .map(|x| map_key_to_control(*x))
-                .flatten()
-                .collect::<Vec<Controls>>();
-            let mut key_b = vec![0; 11];
-            for i in rkeys {
-                match i {
-                    Controls::A => {
-                        key_b[0] = 1;
-                    }
-                    Controls::B => {
-                        key_b[1] = 1;
-                    }
-                    Controls::X => {
-                        key_b[2] = 1;
-                    }
-                    Controls::Y => {
-                        key_b[3] = 1;
-                    }
-                    Controls::Left => {
-                        key_b[4] = 1;
-                    }
-                    Controls::Right => {
-                        key_b[5] = 1;
-                    }
-                    Controls::Up => {
-                        key_b[6] = 1;
-                    }
-                    Controls::Down => {
-                        key_b[7] = 1;
-                    }
-                    Controls::Start => {
-                        key_b[8] = 1;
-                    }
-                    Controls::LeftTrigger => {
-                        key_b[9] = 1;
-                    }
-                    Controls::RightTrigger => {
-                        key_b[10] = 1;
+            4 => {
+                //pullControls(writeLoc)->Controls
+                //writes the currently pressed controls to ptr, in (A,B,X,Y,Left,Right,Up,Down,Start,LTrigger,RTrigger) order.
+                let ptr = machine.core.stack.pop_i32(&mut machine.core.srp) as usize;
+                let rkeys = self
+                    .display
+                    .pull_keys()
+                    .iter()
+                    .map(|x| map_key_to_control(*x))
+                    .flatten()
+                    .collect::<Vec<Controls>>();
+                let mut key_b = vec![0; 11];
+                for i in rkeys {
+                    match i {
+                        Controls::A => {
+                            key_b[0] = 1;
+                        }
+                        Controls::B => {
+                            key_b[1] = 1;
+                        }
+                        Controls::X => {
+                            key_b[2] = 1;
+                        }
+                        Controls::Y => {
+                            key_b[3] = 1;
+                        }
+                        Controls::Left => {
+                            key_b[4] = 1;
+                        }
+                        Controls::Right => {
+                            key_b[5] = 1;
+                        }
+                        Controls::Up => {
+                            key_b[6] = 1;
+                        }
+                        Controls::Down => {
+                            key_b[7] = 1;
+                        }
+                        Controls::Start => {
+                            key_b[8] = 1;
+                        }
+                        Controls::LeftTrigger => {
+                            key_b[9] = 1;
+                        }
+                        Controls::RightTrigger => {
+                            key_b[10] = 1;
+                        }
                     }
                 }
+                machine
+                    .memory
+                    .write_range(ptr..ptr + 11, key_b, &mut machine.core);
             }
-            machine
-                .memory
-                .write_range(ptr..ptr + 11, key_b, &mut machine.core);
+            _ => {}
+        }
+    }
+    fn reset(&mut self) {
+        self.ptrs = GraphicsPtrs::new();
+        self.controls.clear();
+    }
+    //held controls, then the atlas, layer and sprite pointers as i32s
+    fn snapshot(&self) -> Vec<i16> {
+        let mut words: Vec<i16> = (0..CONTROLS).map(|reg| self.control(reg)).collect();
+        words.extend(convert_u32_to_i16(self.ptrs.atlas as u32));
+        for ptrs in [&self.ptrs.layers, &self.ptrs.sprites] {
+            words.push(ptrs.len() as i16);
+            words.extend(ptrs.iter().flat_map(|ptr| convert_u32_to_i16(*ptr as u32)));
+        }
+        words
+    }
+    fn mmio_read(&self, machine: &Machine, reg: usize) -> i16 {
+        if reg < SCROLL_REGS {
+            return self.control(reg);
+        }
+        match self.ptrs.layers.get((reg - SCROLL_REGS) / 2) {
+            Some(ptr) => machine.memory.read(ptr + 1 + reg % 2, machine),
+            None => 0,
+        }
+    }
+    fn mmio_write(&mut self, machine: &mut Machine, reg: usize, value: i16) {
+        if reg < SCROLL_REGS {
+            return;
+        }
+        if let Some(ptr) = self.ptrs.layers.get((reg - SCROLL_REGS) / 2) {
+            machine.memory.write(ptr + 1 + reg % 2, value, &mut machine.core);
         }
-        _ => {}
     }
 }
-fn get_gs(machine: &mut Machine, device_id: usize) -> &mut GraphicsSystem {
-    (if let RawDevice::Graphics(gs) = &mut machine.devices[device_id].contents {
-        Some(gs)
-    } else {
-        None
-    })
-    .expect("Couldn't get graphics system")
-}
-fn load_atlas(ptr: usize, machine: &mut Machine, device_id: usize) {
+fn load_atlas(ptr: usize, machine: &Machine, gs: &mut GraphicsSystem) {
     //[atlas]
     // i16 len
     // [u32*64; len] tiles
@@ -209,9 +220,9 @@ fn load_atlas(ptr: usize, machine: &mut Machine, device_id: usize) {
         .chunks(64)
         .map(|x| x.try_into().unwrap())
         .collect::<Vec<[u32; 64]>>();
-    get_gs(machine, device_id).atlas.borrow_mut().tiles = tiles;
+    gs.atlas.borrow_mut().tiles = tiles;
 }
-fn load_sprite(ptr: usize, machine: &mut Machine, device_id: usize) {
+fn load_sprite(ptr: usize, machine: &Machine, gs: &mut GraphicsSystem) {
     //[sprite layout]
     // i16 id
     // i16 x
@@ -232,7 +243,6 @@ fn load_sprite(ptr: usize, machine: &mut Machine, device_id: usize) {
         .iter()
         .map(|x| *x as usize)
         .collect();
-    let gs: &mut GraphicsSystem = get_gs(machine, device_id);
     match gs.sprite_exists(rsprite[0] as u8) {
         true => {
             let sprite = gs.get_sprite(rsprite[0] as u8);
@@ -256,7 +266,7 @@ fn load_sprite(ptr: usize, machine: &mut Machine, device_id: usize) {
         }
     }
 }
-fn load_layer(ptr: usize, machine: &mut Machine, device_id: usize, scanlines: usize) {
+fn load_layer(ptr: usize, machine: &Machine, gs: &mut GraphicsSystem, scanlines: usize) {
     //[BGLayer layout]
     // i16 id
     // i16 xOffset
@@ -335,7 +345,6 @@ fn load_layer(ptr: usize, machine: &mut Machine, device_id: usize, scanlines: us
         .iter()
         .map(|x| *x as usize)
         .collect();
-    let gs: &mut GraphicsSystem = get_gs(machine, device_id);
     let layer = &mut gs.background_layers[id as usize];
     layer.tilemap.height = tilemap_height as usize;
     layer.tilemap.width = tilemap_width as usize;
@@ -410,7 +419,16 @@ struct GraphicsPtrs {
     layers: Vec<usize>,
     atlas: usize,
 }
-#[derive(Debug, PartialEq)]
+impl GraphicsPtrs {
+    fn new() -> GraphicsPtrs {
+        GraphicsPtrs {
+            sprites: vec![],
+            layers: vec![],
+            atlas: 0,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Controls {
     A,
     B,
@@ -442,7 +460,12 @@ fn map_key_to_control(key: Key) -> Option<Controls> {
     }
 }
 impl GraphicsSystem {
-    pub fn new(resolution: [u32; 2]) -> GraphicsSystem {
+    //1 while the control with index reg was held at the last render
+    fn control(&self, reg: usize) -> i16 {
+        self.controls.iter().any(|c| *c as usize == reg) as i16
+    }
+    //a headless system renders into its buffer without opening a window
+    pub fn new(resolution: [u32; 2], scale: Scale, headless: bool) -> GraphicsSystem {
         let mut gs = GraphicsSystem {
             background_layers: vec![],
             sprites: ([0, 0], Vec::new()),
@@ -452,14 +475,11 @@ impl GraphicsSystem {
                 resolution[1] as usize,
                 "Micro-16",
                 61,
-                Scale::X4,
+                scale,
+                headless,
             ),
             controls: Vec::new(),
-            ptrs: GraphicsPtrs {
-                sprites: vec![],
-                layers: vec![],
-                atlas: 0,
-            },
+            ptrs: GraphicsPtrs::new(),
         };
         gs.background_layers.extend([
             BGLayer::new(TileMap::new(
@@ -575,7 +595,7 @@ struct Display {
     width: usize,
     height: usize,
     buffer: Vec<u32>, //[[u32;width];height]
-    window: Window,
+    window: Option<Window>,
 }
 type Tile = [u32; 64]; //8x8 row order
 pub type Point = [i32; 2];
@@ -752,21 +772,32 @@ impl TileAtlas {
     }
 }
 impl Display {
-    fn new(width: usize, height: usize, title: &str, target_fps: usize, scale: Scale) -> Self {
-        let mut window = Window::new(
-            title,
-            width,
-            height,
-            WindowOptions {
-                scale_mode: minifb::ScaleMode::Pillarbox,
-                scale,
-                resize: true,
-                ..WindowOptions::default()
-            },
-        )
-        .expect("Unable to open the window");
-
-        window.set_target_fps(target_fps);
+    fn new(
+        width: usize,
+        height: usize,
+        title: &str,
+        target_fps: usize,
+        scale: Scale,
+        headless: bool,
+    ) -> Self {
+        let window = if headless {
+            None
+        } else {
+            let mut window = Window::new(
+                title,
+                width,
+                height,
+                WindowOptions {
+                    scale_mode: minifb::ScaleMode::Pillarbox,
+                    scale,
+                    resize: true,
+                    ..WindowOptions::default()
+                },
+            )
+            .expect("Unable to open the window");
+            window.set_target_fps(target_fps);
+            Some(window)
+        };
         Self {
             width,
             height,
@@ -775,19 +806,25 @@ impl Display {
         }
     }
     fn render(&mut self) {
-        if self.window.is_open() {
-            self.window
-                .update_with_buffer(self.buffer.as_slice(), self.width, self.height)
-                .err();
-        } else {
-            return;
+        if let Some(window) = &mut self.window {
+            if window.is_open() {
+                window
+                    .update_with_buffer(self.buffer.as_slice(), self.width, self.height)
+                    .err();
+            }
         }
     }
     fn pull_keys(&self) -> Vec<Key> {
-        self.window.get_keys()
+        match &self.window {
+            Some(window) => window.get_keys(),
+            None => vec![],
+        }
     }
     fn is_open(&self) -> bool {
-        self.window.is_open()
+        match &self.window {
+            Some(window) => window.is_open(),
+            None => true,
+        }
     }
     fn clear(&mut self) {
         self.buffer.fill(0);
@@ -0,0 +1,100 @@
+use crate::devices::{Device, DeviceInfo, DeviceKind};
+use crate::vm::{DataType, Machine};
+use std::fmt;
+//Status pushed on top of a host call's results. A host function reports its own errors as
+//positive statuses, the negative ones come from the device.
+pub const HOST_OK: i16 = 0;
+pub const HOST_NO_FUNCTION: i16 = -1;
+//argc is negative or more than the stack holds
+pub const HOST_BAD_ARGS: i16 = -2;
+//Values pushed back for the guest in order, or the status to push instead
+pub type HostResult = Result<Vec<DataType>, i16>;
+//Called with the words the guest passed, first pushed first
+pub type HostFn = Box<dyn FnMut(&mut Machine, &[i16]) -> HostResult>;
+//Rust closures guests can call by id, see Machine::register_host_fn
+#[derive(Default)]
+pub struct HostCalls {
+    fns: Vec<(String, HostFn)>,
+}
+impl fmt::Debug for HostCalls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostCalls")
+            .field("fns", &self.names().collect::<Vec<&str>>())
+            .finish()
+    }
+}
+impl HostCalls {
+    pub fn new() -> HostCalls {
+        HostCalls { fns: vec![] }
+    }
+    //returns the id guests call f by
+    pub fn register(&mut self, name: &str, f: HostFn) -> usize {
+        self.fns.push((name.to_string(), f));
+        self.fns.len() - 1
+    }
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.fns.iter().map(|(name, _)| name.as_str())
+    }
+}
+impl Device for HostCalls {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            kind: DeviceKind::Host as i16,
+            version: 1,
+            caps: 0,
+        }
+    }
+    fn cycles(&self, command: i16) -> u64 {
+        match command {
+            0 => 16,
+            _ => 4,
+        }
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        if command != 0 {
+            return;
+        }
+        //call(fn: i16, argc: i16, args: argc words) -> results, status
+        //the guest pushes the args, then argc, then fn
+        let id = machine.core.stack.pop(&mut machine.core.srp) as usize;
+        let argc_word = machine.core.stack.pop(&mut machine.core.srp);
+        let srp = machine.core.srp;
+        let Some(argc) = usize::try_from(argc_word).ok().filter(|argc| *argc <= srp) else {
+            if machine.debug {
+                println!("IO.host.call #{} with argc {} -> bad args", id, argc_word);
+            }
+            machine
+                .core
+                .stack
+                .push(DataType::Int(HOST_BAD_ARGS), &mut machine.core.srp);
+            return;
+        };
+        let start = machine.core.stack.below(srp, argc);
+        let args = machine.core.stack.read_bytes(start, argc);
+        machine
+            .core
+            .stack
+            .pop_range(start..srp, &mut machine.core.srp);
+        let result = match self.fns.get_mut(id) {
+            Some((_, f)) => f(machine, &args),
+            None => Err(HOST_NO_FUNCTION),
+        };
+        if machine.debug {
+            let name = self.fns.get(id).map_or("?", |(name, _)| name.as_str());
+            println!("IO.host.call {}({:?}) -> {:?}", name, args, result);
+        }
+        let status = match result {
+            Ok(values) => {
+                for value in values {
+                    machine.core.stack.push(value, &mut machine.core.srp);
+                }
+                HOST_OK
+            }
+            Err(status) => status,
+        };
+        machine
+            .core
+            .stack
+            .push(DataType::Int(status), &mut machine.core.srp);
+    }
+}
@@ -1,41 +1,162 @@
-use crate::Disk;
+use crate::config::{MachineConfig, window_scale};
 use crate::devices::audio::AudioDevice;
 use crate::devices::clock::Clock;
+use crate::devices::disk::{Disk, DiskDrive};
 use crate::devices::gfx::GraphicsSystem;
+use crate::devices::host::HostCalls;
+use crate::devices::perf::Perf;
+use crate::error::{VmError, fault};
+use crate::memmap::MMIO_SLOT_LEN;
 use crate::vm::Machine;
+use serde::Deserialize;
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
 pub mod audio;
 pub mod clock;
 pub mod disk;
 pub mod gfx;
-#[derive(Debug)]
-pub struct Device {
-    pub driver: fn(machine: &mut Machine, command: i16, device_id: usize),
-    pub contents: RawDevice,
-}
-#[derive(Debug)]
-pub enum RawDevice {
-    Disk(Disk),
-    Audio(AudioDevice),
-    Clock(Clock),
-    Graphics(GraphicsSystem),
-}
-pub fn get_device_list() -> Vec<Device> {
-    vec![
-        Device {
-            driver: disk::driver,
-            contents: RawDevice::Disk(Disk::new()),
-        },
-        Device {
-            driver: audio::driver,
-            contents: RawDevice::Audio(AudioDevice::new()),
-        },
-        Device {
-            driver: clock::driver,
-            contents: RawDevice::Clock(Clock::new()),
-        },
-        Device {
-            driver: gfx::driver,
-            contents: RawDevice::Graphics(GraphicsSystem::new([320, 240])),
-        },
-    ]
+pub mod host;
+pub mod perf;
+//A peripheral in one of the machine's slots. IO device,command runs command with its arguments
+//on the guest stack, Load/Store in the slot's part of the MMIO window go to mmio_read and
+//mmio_write. The built in devices are registered the same way an embedder's are, see
+//Machine::register_device.
+pub trait Device: Any + fmt::Debug {
+    fn command(&mut self, machine: &mut Machine, command: i16);
+    //what QueryDevice tells guests about this device
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            kind: CUSTOM_DEVICE,
+            version: 1,
+            caps: 0,
+        }
+    }
+    //cycles an IO command costs on top of the IO instruction itself
+    fn cycles(&self, _command: i16) -> u64 {
+        4
+    }
+    //back to power-on state, Machine::run resets every device before it boots
+    fn reset(&mut self) {}
+    //called every DEVICE_TICK cycles of guest time
+    fn tick(&mut self, _machine: &mut Machine) {}
+    //polled while a core is in Wait, true wakes every waiting core
+    fn event(&mut self) -> bool {
+        false
+    }
+    //the device's own state as words, shown by the debugger
+    fn snapshot(&self) -> Vec<i16> {
+        vec![]
+    }
+    fn debug_dump(&self) -> String {
+        format!("{:?}", self)
+    }
+    //registers that don't exist read as 0 and ignore writes
+    fn mmio_read(&self, _machine: &Machine, _reg: usize) -> i16 {
+        0
+    }
+    fn mmio_write(&mut self, _machine: &mut Machine, _reg: usize, _value: i16) {}
+}
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceInfo {
+    //a DeviceKind, or CUSTOM_DEVICE and up for an embedder's devices
+    pub kind: i16,
+    //bumped when the command set or the registers change
+    pub version: i16,
+    //CAP_* flags
+    pub caps: i16,
+}
+pub const CUSTOM_DEVICE: i16 = 256;
+//has registers in the MMIO window
+pub const CAP_MMIO: i16 = 1;
+//drives a real window or speakers, unset when headless
+pub const CAP_OUTPUT: i16 = 2;
+//reads host input
+pub const CAP_INPUT: i16 = 4;
+//Shared so a device can be handed the machine that owns it while it runs
+pub type DeviceCell = Rc<RefCell<dyn Device>>;
+//cycles of guest time between device ticks
+pub const DEVICE_TICK: u64 = 4_000;
+//The built in devices, by their name in a MachineConfig. The discriminant is the kind
+//QueryDevice reports, 0 is an empty slot.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeviceKind {
+    Disk = 1,
+    Audio = 2,
+    Clock = 3,
+    Gfx = 4,
+    Perf = 5,
+    Host = 6,
+}
+pub fn get_device_list(config: &MachineConfig) -> Vec<DeviceCell> {
+    let scale = window_scale(config.scale).expect("validated config");
+    let device = |kind| -> DeviceCell {
+        match kind {
+            DeviceKind::Disk => Rc::new(RefCell::new(DiskDrive::new(Disk::new()))),
+            DeviceKind::Audio => Rc::new(RefCell::new(AudioDevice::new(
+                config.sample_rate,
+                config.headless,
+            ))),
+            DeviceKind::Clock => Rc::new(RefCell::new(Clock::new())),
+            DeviceKind::Gfx => Rc::new(RefCell::new(GraphicsSystem::new(
+                config.resolution,
+                scale,
+                config.headless,
+            ))),
+            DeviceKind::Perf => Rc::new(RefCell::new(Perf::new())),
+            DeviceKind::Host => Rc::new(RefCell::new(HostCalls::new())),
+        }
+    };
+    config.devices.iter().map(|kind| device(*kind)).collect()
+}
+//IO device,command. The device stays borrowed while it runs, so IO back into it from inside,
+//say from a host fn, faults.
+pub fn command(machine: &mut Machine, ip: usize, id: i32, command: i16) {
+    let device = match usize::try_from(id).ok().and_then(|id| machine.devices.get(id)) {
+        Some(device) => device.clone(),
+        None => fault(VmError::NoDevice { ip, device: id }),
+    };
+    let Ok(mut device) = device.try_borrow_mut() else {
+        fault(VmError::DeviceBusy {
+            ip,
+            device: id as usize,
+        })
+    };
+    machine.core.cycles += device.cycles(command);
+    device.command(machine, command);
+}
+pub fn tick(machine: &mut Machine) {
+    for device in machine.devices.clone() {
+        device.borrow_mut().tick(machine);
+    }
+}
+pub fn poll_events(machine: &Machine) -> bool {
+    let mut event = false;
+    for device in &machine.devices {
+        event |= device.try_borrow_mut().is_ok_and(|mut device| device.event());
+    }
+    event
+}
+//offset is relative to the start of the MMIO window, a device reading its own registers
+//while it runs sees 0
+pub fn mmio_read(machine: &Machine, offset: usize) -> i16 {
+    let (slot, reg) = (offset / MMIO_SLOT_LEN, offset % MMIO_SLOT_LEN);
+    match machine.devices.get(slot).and_then(|device| device.try_borrow().ok()) {
+        Some(device) => device.mmio_read(machine, reg),
+        None => 0,
+    }
+}
+//Hands queued MMIO stores to their devices, in the order they were made
+pub fn flush_mmio(machine: &mut Machine) {
+    let writes = std::mem::take(&mut machine.memory.mmio_writes);
+    for (offset, value) in writes {
+        let (slot, reg) = (offset / MMIO_SLOT_LEN, offset % MMIO_SLOT_LEN);
+        if let Some(device) = machine.devices.get(slot).cloned()
+            && let Ok(mut device) = device.try_borrow_mut()
+        {
+            device.mmio_write(machine, reg, value);
+        }
+    }
 }
@@ -0,0 +1,107 @@
+use crate::devices::{CAP_MMIO, Device, DeviceInfo, DeviceKind};
+use crate::error::{Access, VmError, fault};
+use crate::util::convert_i32_to_i16;
+use crate::vm::{DataType, Machine};
+use std::time::{Duration, Instant};
+//Counters the rest of the machine bumps for the perf device to report. Instructions and
+//cycles already live in Machine::freq and Core::cycles, the stack tracks its own high water.
+#[derive(Debug, Default)]
+pub struct PerfCounters {
+    pub frames: u64,
+    pub io_calls: u64,
+    //time between the last two renders
+    pub frame_time: Duration,
+    last_render: Option<Instant>,
+}
+impl PerfCounters {
+    pub fn new() -> PerfCounters {
+        PerfCounters::default()
+    }
+    pub fn frame_rendered(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_render {
+            self.frame_time = now - last;
+        }
+        self.last_render = Some(now);
+        self.frames += 1;
+    }
+}
+#[derive(Debug, Default)]
+pub struct Perf {}
+impl Perf {
+    pub fn new() -> Self {
+        Perf {}
+    }
+}
+//instructions, cycles, frames, io calls, stack high water (words), frame time (us)
+const COUNTERS: usize = 6;
+//Counters wrap at i32, guests are expected to diff two reads
+fn counter(machine: &Machine, id: usize) -> i32 {
+    let value = match id {
+        0 => machine.freq.0,
+        1 => machine.core.cycles,
+        2 => machine.perf.frames,
+        3 => machine.perf.io_calls,
+        4 => machine.core.stack.high_water as u64,
+        5 => machine.perf.frame_time.as_micros() as u64,
+        _ => 0,
+    };
+    value as i32
+}
+impl Device for Perf {
+    fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            kind: DeviceKind::Perf as i16,
+            version: 1,
+            caps: CAP_MMIO,
+        }
+    }
+    fn cycles(&self, command: i16) -> u64 {
+        match command {
+            1 => 16,
+            _ => 4,
+        }
+    }
+    fn command(&mut self, machine: &mut Machine, command: i16) {
+        match command {
+            0 => {
+                //read(counter) -> i32
+                let id = machine.core.stack.pop(&mut machine.core.srp) as usize;
+                let value = counter(machine, id);
+                machine
+                    .core
+                    .stack
+                    .push(DataType::Int32(value), &mut machine.core.srp);
+                if machine.debug {
+                    println!("IO.perf.read {} -> {}", id, value);
+                }
+            }
+            1 => {
+                //snapshot(dest), writes every counter as an i32 in read's order
+                let dest = machine.core.stack.pop_i32(&mut machine.core.srp);
+                //a negative dest is below main memory
+                let Ok(dest) = usize::try_from(dest) else {
+                    fault(VmError::Unmapped {
+                        addr: dest as u32 as usize,
+                        access: Access::Write,
+                    });
+                };
+                let words: Vec<i16> = (0..COUNTERS)
+                    .flat_map(|id| convert_i32_to_i16(counter(machine, id)))
+                    .collect();
+                machine
+                    .memory
+                    .write_range(dest..dest + words.len(), words, &mut machine.core);
+                if machine.debug {
+                    println!("IO.perf.snapshot %{}", dest);
+                }
+            }
+            _ => {}
+        }
+    }
+    //[registers]
+    // every counter as an i32, counter n at 2n..2n+2
+    fn mmio_read(&self, machine: &Machine, reg: usize) -> i16 {
+        convert_i32_to_i16(counter(machine, reg / 2))[reg % 2]
+    }
+}
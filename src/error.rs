@@ -0,0 +1,142 @@
+use crate::memmap::{Perms, RegionKind};
+use std::fmt;
+use std::panic;
+use std::sync::Once;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    //addr is not covered by any region (or lies past the top of the stack)
+    Unmapped {
+        addr: usize,
+        access: Access,
+    },
+    //the region at addr doesn't grant the requested access
+    AccessFault {
+        addr: usize,
+        access: Access,
+        region: RegionKind,
+        perms: Perms,
+    },
+    //a privileged instruction was executed outside of the loader
+    Privileged {
+        ip: usize,
+    },
+    //MapRegion with a kind that isn't a RegionKind id
+    InvalidRegionKind {
+        ip: usize,
+        kind: i16,
+    },
+    //the word after an operand escape at addr isn't a known tag
+    InvalidOperand {
+        addr: usize,
+        tag: i16,
+    },
+    //a push or Enter would grow the stack past its limit, ip is the core's IP at the time
+    StackOverflow {
+        ip: usize,
+        depth: usize,
+        limit: usize,
+    },
+    //a pop or return needed more words than the stack holds
+    StackUnderflow {
+        ip: usize,
+        depth: usize,
+    },
+    //CoResume of a handle that isn't suspended, or CoYield with no coroutine running
+    BadCoroutine {
+        ip: usize,
+        handle: Option<usize>,
+    },
+    //DivEx or ModEx with a zero divisor
+    DivideByZero {
+        ip: usize,
+    },
+    //IO to a slot the machine doesn't have
+    NoDevice {
+        ip: usize,
+        device: i32,
+    },
+    //IO to a device that is already running a command, like a host fn calling back into
+    //its own slot
+    DeviceBusy {
+        ip: usize,
+        device: usize,
+    },
+}
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+            Access::Execute => write!(f, "execute"),
+        }
+    }
+}
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmError::Unmapped { addr, access } => {
+                write!(f, "Unmapped {} at %{}", access, addr)
+            }
+            VmError::AccessFault {
+                addr,
+                access,
+                region,
+                perms,
+            } => write!(
+                f,
+                "Access fault: {} at %{} in {:?} region ({})",
+                access, addr, region, perms
+            ),
+            VmError::Privileged { ip } => {
+                write!(f, "Privileged instruction outside of loader at %{}", ip)
+            }
+            VmError::InvalidRegionKind { ip, kind } => {
+                write!(f, "Invalid region kind {} at %{}", kind, ip)
+            }
+            VmError::InvalidOperand { addr, tag } => {
+                write!(f, "Invalid operand tag {} at %{}", tag, addr)
+            }
+            VmError::StackOverflow { ip, depth, limit } => write!(
+                f,
+                "Stack overflow at %{} ({} words, call depth {})",
+                ip, limit, depth
+            ),
+            VmError::StackUnderflow { ip, depth } => {
+                write!(f, "Stack underflow at %{} (call depth {})", ip, depth)
+            }
+            VmError::BadCoroutine { ip, handle } => match handle {
+                Some(handle) => write!(f, "Coroutine #{} can't be resumed at %{}", handle, ip),
+                None => write!(f, "Yield outside of a coroutine at %{}", ip),
+            },
+            VmError::DivideByZero { ip } => write!(f, "Division by zero at %{}", ip),
+            VmError::NoDevice { ip, device } => {
+                write!(f, "IO to missing device {} at %{}", device, ip)
+            }
+            VmError::DeviceBusy { ip, device } => {
+                write!(f, "IO to busy device {} at %{}", device, ip)
+            }
+        }
+    }
+}
+//Raises a fault. Faults unwind out of the current instruction and are caught by Machine::run.
+pub fn fault(err: VmError) -> ! {
+    panic::panic_any(err)
+}
+//Keeps the default panic hook from printing faults, Machine::panic reports those itself
+pub fn install_fault_hook() {
+    static HOOK: Once = Once::new();
+    HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if info.payload().downcast_ref::<VmError>().is_none() {
+                default_hook(info);
+            }
+        }));
+    });
+}
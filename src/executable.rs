@@ -1,11 +1,13 @@
-use crate::Bytecode::{
-    ArgCount, Argument, BlockLoc, Command, ConstantLoc, Float, FunctionRef, Int, Int32, Register,
-    SymbolSectionLen,
+use crate::executable::Bytecode::{
+    ArgCount, Argument, BlockLoc, Command, ConstantLoc, Float, FunctionRef, Int, Int32, JumpTable,
+    RegOffset, Register, SymbolSectionLen,
 };
-use crate::CommandType;
-use crate::CommandType::{Add, IO, Jump, Load, Mov, Push, R1, R2, R3};
+use crate::backtrace::SymbolMap;
 use crate::devices::disk::{Disk, DiskSection, DiskSectionType};
+use crate::memmap::{Perms, Region, RegionKind};
 use crate::util::*;
+use crate::vm::CommandType;
+use crate::vm::CommandType::{Add, IO, Jump, Load, MapRegion, Push, R1, R2, R3};
 use std::collections::HashMap;
 pub struct Library {
     name: String,
@@ -21,6 +23,15 @@ impl Library {
         }
     }
     pub fn add_constant(&mut self, constant: Vec<Data>) -> usize {
+        let constant = constant
+            .into_iter()
+            .map(|data| match data {
+                Data::FunctionRef(func_ref) => {
+                    Data::FunctionRef(func_ref.replace("self::", &format!("{}::", self.name)))
+                }
+                data => data,
+            })
+            .collect();
         self.constants.push(constant);
         self.constants.len() - 1
     }
@@ -113,15 +124,8 @@ impl SymbolTable {
     }
     pub fn setup_stack(&self) -> Vec<i16> {
         flatten_vec(vec![
-            vec![pack_command(CommandType::AddEx)],
-            pack_register(CommandType::SP),
+            vec![pack_command(CommandType::Enter)],
             pack_i32(self.len() as i32),
-            vec![pack_command(Mov)],
-            pack_register(CommandType::EX1),
-            pack_register(CommandType::SP),
-            vec![pack_command(Mov)],
-            pack_register(CommandType::EX1),
-            pack_register(CommandType::SRP),
         ])
     }
 }
@@ -146,6 +150,10 @@ pub enum Bytecode {
     SymbolSectionLen(),
     Argument(usize),
     ArgCount(),
+    //address of one of the fn's jump tables, see Fn::add_jump_table
+    JumpTable(usize),
+    //[reg + offset]
+    RegOffset(CommandType, i32),
 }
 #[derive(Debug, Clone)]
 pub enum Data {
@@ -154,6 +162,10 @@ pub enum Data {
     Int(i16),
     Int32(i32),
     ConstantLoc(usize),
+    //i32 address of a function, for CallIndirect/JumpIndirect tables
+    FunctionRef(String),
+    //i32 address of a block in the named function
+    BlockRef(String, usize),
 }
 fn get_data_len(data: &Data) -> usize {
     match data {
@@ -162,6 +174,8 @@ fn get_data_len(data: &Data) -> usize {
         Data::Int(_i) => 1,
         Data::Int32(_i) => 2,
         Data::ConstantLoc(_c) => 2,
+        Data::FunctionRef(_f) => 2,
+        Data::BlockRef(_f, _b) => 2,
     }
 }
 #[derive(Debug, Clone)]
@@ -186,7 +200,15 @@ impl ConstantTable {
         }
         return 0;
     }
-    fn serialize(&self, base: usize) -> Vec<i16> {
+    fn len(&self) -> usize {
+        self.data_sec.concat().iter().map(get_data_len).sum()
+    }
+    fn serialize(
+        &self,
+        base: usize,
+        fn_map: &HashMap<String, usize>,
+        block_map: &HashMap<String, Vec<usize>>,
+    ) -> Vec<i16> {
         self.data_sec
             .concat()
             .iter()
@@ -195,6 +217,8 @@ impl ConstantTable {
                 Data::ConstantLoc(c) => {
                     convert_i32_to_i16((self.get_constant_offset(*c) + base) as i32).to_vec()
                 }
+                Data::FunctionRef(f) => convert_i32_to_i16(fn_map[f] as i32).to_vec(),
+                Data::BlockRef(f, b) => convert_i32_to_i16(block_map[f][*b] as i32).to_vec(),
                 Data::Float(f) => convert_float(*f),
                 Data::Int32(i) => convert_i32_to_i16(*i).to_vec(),
                 Data::Int(i) => vec![*i],
@@ -283,15 +307,26 @@ impl Executable {
 
             //loopEnd:
             //Jump 256
-            loader: Self::default_loader(512, 6),
+            loader: Self::default_loader(512, 6, &[]),
             max_loader_len: 512,
         }
     }
-    fn default_loader(max_loader_len: i16, header_len: i16) -> Vec<i16> {
+    //regions are mapped after the sectors are loaded, right before jumping into the executable
+    fn default_loader(max_loader_len: i16, header_len: i16, regions: &[Region]) -> Vec<i16> {
         let mut f = Fn::new("loader".to_string(), 0);
         f.symbol_enabled = false;
+        let mut map_regions = vec![];
+        for region in regions {
+            map_regions.extend([
+                Command(MapRegion),
+                Int32(region.range.start as i32),
+                Int32(region.range.len() as i32),
+                Int(region.kind.id()),
+                Int(region.perms.bits()),
+            ]);
+        }
         f.add_block(
-            vec![
+            [
                 Command(Push),
                 Int(0), //dest
                 Command(Push),
@@ -322,9 +357,11 @@ impl Executable {
                 Command(IO),
                 Int(0),
                 Int(2), //loadSectors
-                Command(Jump),
-                Int(max_loader_len + header_len),
-            ],
+            ]
+            .into_iter()
+            .chain(map_regions)
+            .chain([Command(Jump), Int(max_loader_len + header_len)])
+            .collect(),
             true,
         );
         f.build(0, &HashMap::new(), 0, &ConstantTable::new())
@@ -354,6 +391,23 @@ impl Executable {
         //headers
         offset += header_len + insertion_jump_len;
         let mut main_loc = 0;
+        //jump tables live in the data section, after every other constant. They go into a copy
+        //so the executable's own constants stay as they were added.
+        let mut constants = self.constants.clone();
+        for func in self.fns.iter_mut() {
+            func.jump_table_ids = func
+                .jump_tables
+                .iter()
+                .map(|table| {
+                    constants.add_constant(
+                        table
+                            .iter()
+                            .map(|b| Data::BlockRef(func.name.clone(), *b as usize))
+                            .collect(),
+                    )
+                })
+                .collect();
+        }
         let data_sec = self.fns.iter_mut().fold(offset + 1, |acc, func| {
             if func.name == "main" {
                 main_loc = acc;
@@ -362,10 +416,40 @@ impl Executable {
             acc + func.len()
         }) as usize;
         //TODO: handle contant building
+        let mut block_map: HashMap<String, Vec<usize>> = HashMap::new();
         for func in self.fns.iter_mut() {
-            bytecode.extend(func.build(fn_map[&func.name], &fn_map, data_sec, &self.constants))
+            block_map.insert(func.name.clone(), func.block_addrs(fn_map[&func.name]));
+            bytecode.extend(func.build(fn_map[&func.name], &fn_map, data_sec, &constants))
         }
+        let data_len = constants.len();
+        let loader = Self::default_loader(
+            self.max_loader_len,
+            header_len as i16,
+            &[
+                Region::new(
+                    RegionKind::Loader,
+                    0..self.max_loader_len as usize,
+                    Perms::RX,
+                ),
+                //headers, insertion jump & fns
+                Region::new(
+                    RegionKind::Code,
+                    offset + 1 - (header_len + insertion_jump_len)..data_sec,
+                    Perms::RX,
+                ),
+                Region::new(
+                    RegionKind::Constants,
+                    data_sec..data_sec + data_len,
+                    Perms::RW,
+                ),
+            ],
+        );
+        self.set_loader(loader);
 
+        let mut symbols = SymbolMap::new();
+        for func in &self.fns {
+            symbols.add(fn_map[&func.name], func.len(), &func.name);
+        }
         Self::insert_bytecode_into_disk(
             &self,
             disk,
@@ -374,11 +458,17 @@ impl Executable {
             main_loc,
             header_len + insertion_jump_len,
             debug,
-            self.constants.serialize(data_sec),
+            &constants,
+            constants.serialize(data_sec, &fn_map, &block_map),
         );
+        disk.push(DiskSection {
+            section_type: DiskSectionType::Symbols,
+            id: disk.len() as i16,
+            data: symbols.serialize(),
+        });
     }
     fn print_structure(
-        &self,
+        constants: &ConstantTable,
         bytecode: &Vec<i16>,
         offset: usize,
         header_len: usize,
@@ -394,7 +484,7 @@ impl Executable {
         );
         println!("Bytecode Len: {}", bytecode.len() + 2);
         println!("Code Sector Count: {}", code_sectors);
-        println!("Data Len: {}", self.constants.serialize(0).len());
+        println!("Data Len: {}", constants.len());
         println!("Data Sector Count: {}", data_sectors);
         println!("-------Insertion Jump-------");
         println!("Jump to Entry Point: %{}", entrypoint);
@@ -403,11 +493,11 @@ impl Executable {
             println!("{:07}: {:?}", i * 32, chunk);
         }
         println!("-------Data-------");
-        for (id, data) in self.constants.data_sec.iter().enumerate() {
+        for (id, data) in constants.data_sec.iter().enumerate() {
             for (i, chunk) in data.chunks(32).map(|slice| slice.to_vec()).enumerate() {
                 println!(
                     "Constant {:07}: {:?}",
-                    i * 32 + self.constants.get_constant_offset(id) + offset + bytecode.len(),
+                    i * 32 + constants.get_constant_offset(id) + offset + bytecode.len(),
                     chunk
                 );
             }
@@ -421,13 +511,15 @@ impl Executable {
         entrypoint: usize,
         header_len: usize,
         debug: bool,
+        constants: &ConstantTable,
         data: Vec<i16>,
     ) {
         //(total exe code len/max sector data).ceil()
         let code_sectors = ((offset + bytecode.len()) as f32 / i16::MAX as f32).ceil() as usize;
         let data_sectors = (data.len() as f32 / i16::MAX as f32).ceil() as usize;
         if debug {
-            self.print_structure(
+            Self::print_structure(
+                constants,
                 &bytecode,
                 offset,
                 header_len,
@@ -519,6 +611,9 @@ pub(crate) struct Fn {
     arg_count: usize,
     symbol_table: SymbolTable,
     symbol_enabled: bool,
+    jump_tables: Vec<Vec<isize>>,
+    //constant ids of the tables, assigned by Executable::build
+    jump_table_ids: Vec<usize>,
 }
 impl Fn {
     pub(crate) fn new(name: String, args: usize) -> Fn {
@@ -531,6 +626,8 @@ impl Fn {
             arg_count: args,
             symbol_table: SymbolTable::new(),
             symbol_enabled: true,
+            jump_tables: vec![],
+            jump_table_ids: vec![],
         }
     }
     pub fn new_with_blocks(name: String, args: usize, blocks: Vec<Vec<Bytecode>>) -> Fn {
@@ -543,6 +640,8 @@ impl Fn {
             symbol_table: SymbolTable::new(),
             symbol_enabled: true,
             arg_count: args,
+            jump_tables: vec![],
+            jump_table_ids: vec![],
         };
         for block in blocks {
             f.add_block(block, false);
@@ -556,6 +655,25 @@ impl Fn {
         }
         (self.blocks.len() - 1) as isize
     }
+    //Table of block addresses for Switch, returns the id to use with Bytecode::JumpTable
+    pub(crate) fn add_jump_table(&mut self, blocks: Vec<isize>) -> usize {
+        self.jump_tables.push(blocks);
+        self.jump_tables.len() - 1
+    }
+    fn block_addrs(&self, pos: usize) -> Vec<usize> {
+        let symbol_tbl_len = match self.symbol_enabled {
+            true => self.symbol_table.setup_stack().len(),
+            false => 0,
+        };
+        self.blocks
+            .iter()
+            .scan(pos + 5 + symbol_tbl_len, |acc, b| {
+                let addr = *acc;
+                *acc += self.get_block_len(b);
+                Some(addr)
+            })
+            .collect()
+    }
     fn len(&self) -> usize {
         self
             .blocks
@@ -575,22 +693,12 @@ impl Fn {
         data_sec: usize,
         consts: &ConstantTable,
     ) -> Vec<i16> {
-        let mut block_map: HashMap<usize, usize> = HashMap::new();
         let mut bytecode = Vec::new();
-        let symbol_tbl_len = match self.symbol_enabled {
-            true => self.symbol_table.setup_stack().len(),
-            false => 0,
-        };
         if self.symbol_enabled {
             bytecode.extend(self.symbol_table.setup_stack());
         }
-        self.blocks
-            .iter()
-            .enumerate()
-            .fold(pos + 5 + symbol_tbl_len, |acc, (i, b)| {
-                block_map.insert(i, acc);
-                acc + self.get_block_len(b)
-            });
+        let block_map: HashMap<usize, usize> =
+            self.block_addrs(pos).into_iter().enumerate().collect();
         bytecode.push(19);
         bytecode.extend_from_slice(&pack_i32(block_map[&(self.entrypoint)] as i32));
         for (i, block) in self.blocks.iter_mut().enumerate() {
@@ -604,7 +712,7 @@ impl Fn {
                         SymbolSectionLen() => pack_i32(self.symbol_table.len() as i32),
                         Register(r) => pack_register(*r),
                         Float(f) => pack_float(*f),
-                        Int(i) => vec![*i],
+                        Int(i) => pack_i16(*i),
                         FunctionRef(f) => pack_i32(fn_map[f] as i32),
                         ConstantLoc(c) => {
                             pack_i32((data_sec + consts.get_constant_offset(*c)) as i32)
@@ -627,6 +735,11 @@ impl Fn {
                         }
                         Argument(arg) => pack_i32((*arg as i32) - (self.arg_count as i32)),
                         ArgCount() => pack_i32(self.arg_count as i32),
+                        RegOffset(r, offset) => pack_reg_offset(*r, *offset),
+                        JumpTable(t) => pack_i32(
+                            (data_sec + consts.get_constant_offset(self.jump_table_ids[*t]))
+                                as i32,
+                        ),
                     })
                     .collect::<Vec<Vec<i16>>>(),
             );
@@ -641,7 +754,7 @@ impl Fn {
                 Command(_c) => 1,
                 Register(_r) => 3,
                 Float(_f) => 4,
-                Int(_i) => 1,
+                Int(i) => pack_i16(*i).len(),
                 FunctionRef(_f) => 4,
                 ConstantLoc(_c) => 4,
                 BlockLoc(_b) => 4,
@@ -650,6 +763,8 @@ impl Fn {
                 SymbolSectionLen() => 4,
                 Argument(_a) => 4,
                 ArgCount() => 4,
+                JumpTable(_t) => 4,
+                RegOffset(_r, _o) => 5,
             })
             .collect::<Vec<usize>>()
             .iter()
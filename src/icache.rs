@@ -0,0 +1,222 @@
+use crate::util::get_reg;
+use crate::vm::{CommandType, Core};
+use std::ops::Range;
+pub const MAX_OPERANDS: usize = 4;
+#[derive(Debug, Clone, Copy)]
+pub enum Operand {
+    //i16, i32 and f32 immediates
+    Value(f64),
+    //value of a register at execution time
+    Register(i16),
+    //register plus a constant, for base+offset addressing
+    RegOffset(i16, f64),
+}
+impl Operand {
+    pub fn eval(&self, core: &Core) -> f64 {
+        match self {
+            Operand::Value(v) => *v,
+            Operand::Register(r) => get_reg(*r, core),
+            Operand::RegOffset(r, offset) => get_reg(*r, core) + offset,
+        }
+    }
+}
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
+    pub command: CommandType,
+    pub operands: [Operand; MAX_OPERANDS],
+    pub operand_count: usize,
+    //destination register, for commands that write one
+    pub register: i16,
+    //length in words, including the command
+    pub len: usize,
+}
+impl Instruction {
+    pub fn eval(&self, core: &Core) -> [f64; MAX_OPERANDS] {
+        let mut args = [0.0; MAX_OPERANDS];
+        for (arg, operand) in args.iter_mut().zip(&self.operands[..self.operand_count]) {
+            *arg = operand.eval(core);
+        }
+        args
+    }
+}
+//An operand the fast paths can read without going through f64: a whole number immediate or
+//one of the i16 registers, each its own variant so reading one is a single match
+#[derive(Debug, Clone, Copy)]
+pub enum Src {
+    Imm(i32),
+    R1,
+    R2,
+    R3,
+    R4,
+    R5,
+}
+impl Src {
+    fn new(operand: Operand) -> Option<Src> {
+        match operand {
+            Operand::Value(v) if v.fract() == 0.0 && v.abs() <= i32::MAX as f64 => {
+                Some(Src::Imm(v as i32))
+            }
+            Operand::Register(1) => Some(Src::R1),
+            Operand::Register(2) => Some(Src::R2),
+            Operand::Register(3) => Some(Src::R3),
+            Operand::Register(4) => Some(Src::R4),
+            Operand::Register(13) => Some(Src::R5),
+            _ => None,
+        }
+    }
+    #[inline(always)]
+    pub fn get(self, core: &Core) -> i32 {
+        (match self {
+            Src::Imm(v) => return v,
+            Src::R1 => core.r1,
+            Src::R2 => core.r2,
+            Src::R3 => core.r3,
+            Src::R4 => core.r4,
+            Src::R5 => core.r5,
+        }) as i32
+    }
+}
+//r1-r5
+fn is_int_reg(reg: i16) -> bool {
+    matches!(reg, 1..=4 | 13)
+}
+#[inline(always)]
+pub fn set_int_reg(reg: i16, core: &mut Core, value: i16) {
+    match reg {
+        1 => core.r1 = value,
+        2 => core.r2 = value,
+        3 => core.r3 = value,
+        4 => core.r4 = value,
+        _ => core.r5 = value,
+    }
+}
+//what converting the exact f64 result to an i16 register gives
+#[inline(always)]
+pub fn saturate(value: i64) -> i16 {
+    value.clamp(i16::MIN as i64, i16::MAX as i64) as i16
+}
+//The hot commands with integer operands, decoded once so the interpreter can run them without
+//building args. Each one does what execute does with the same operands, Slow goes to execute.
+#[derive(Debug, Clone, Copy)]
+pub enum Fast {
+    Add(Src, Src),
+    Sub(Src, Src),
+    Mul(Src, Src),
+    And(Src, Src),
+    Or(Src, Src),
+    Xor(Src, Src),
+    Greater(Src, Src),
+    LessThan(Src, Src),
+    //(value, r1-r5)
+    Mov(Src, i16),
+    //(address, r1-r5)
+    Load(Src, i16),
+    //(address, value)
+    Store(Src, Src),
+    Jump(Src),
+    //(address, condition)
+    JumpZero(Src, Src),
+    JumpNotZero(Src, Src),
+    Nop,
+    Slow,
+}
+impl Fast {
+    fn new(inst: &Instruction) -> Fast {
+        let [a, b, ..] = inst.operands.map(Src::new);
+        let reg = inst.register;
+        match (inst.command, a, b) {
+            (CommandType::Add, Some(a), Some(b)) => Fast::Add(a, b),
+            (CommandType::Sub, Some(a), Some(b)) => Fast::Sub(a, b),
+            (CommandType::Mul, Some(a), Some(b)) => Fast::Mul(a, b),
+            (CommandType::And, Some(a), Some(b)) => Fast::And(a, b),
+            (CommandType::Or, Some(a), Some(b)) => Fast::Or(a, b),
+            (CommandType::Xor, Some(a), Some(b)) => Fast::Xor(a, b),
+            (CommandType::Greater, Some(a), Some(b)) => Fast::Greater(a, b),
+            (CommandType::LessThan, Some(a), Some(b)) => Fast::LessThan(a, b),
+            (CommandType::Mov, Some(a), _) if is_int_reg(reg) => Fast::Mov(a, reg),
+            (CommandType::Load, Some(a), _) if is_int_reg(reg) => Fast::Load(a, reg),
+            (CommandType::Store, Some(a), Some(b)) => Fast::Store(a, b),
+            (CommandType::Jump, Some(a), _) => Fast::Jump(a),
+            (CommandType::JumpZero, Some(a), Some(b)) => Fast::JumpZero(a, b),
+            (CommandType::JumpNotZero, Some(a), Some(b)) => Fast::JumpNotZero(a, b),
+            (CommandType::NOP, _, _) => Fast::Nop,
+            _ => Fast::Slow,
+        }
+    }
+}
+//A cached instruction's fast form with what the interpreter needs to step past it
+#[derive(Debug, Clone, Copy)]
+pub struct FastOp {
+    pub fast: Fast,
+    pub len: u32,
+    pub cycles: u32,
+}
+const SLOW: FastOp = FastOp {
+    fast: Fast::Slow,
+    len: 0,
+    cycles: 0,
+};
+//Decoded instructions indexed by IP. Code sits at the bottom of memory, so a flat
+//table is cheaper than hashing. Any write to a word covered by a cached instruction drops it.
+#[derive(Debug)]
+pub struct InstructionCache {
+    pub enabled: bool,
+    instructions: Vec<Option<Instruction>>,
+    //fast forms by IP, small so the hot loop stays in cache. Slow where nothing is cached.
+    fast: Vec<FastOp>,
+    max_len: usize,
+    cached: usize,
+}
+impl InstructionCache {
+    pub fn new() -> InstructionCache {
+        InstructionCache {
+            enabled: true,
+            instructions: vec![],
+            fast: vec![],
+            max_len: 1,
+            cached: 0,
+        }
+    }
+    pub fn get(&self, ip: usize) -> Option<&Instruction> {
+        self.instructions.get(ip).and_then(|i| i.as_ref())
+    }
+    #[inline(always)]
+    pub fn get_fast(&self, ip: usize) -> FastOp {
+        self.fast.get(ip).copied().unwrap_or(SLOW)
+    }
+    pub fn insert(&mut self, ip: usize, inst: Instruction) {
+        if self.instructions.len() <= ip {
+            self.instructions.resize(ip + 1, None);
+            self.fast.resize(ip + 1, SLOW);
+        }
+        self.max_len = self.max_len.max(inst.len);
+        self.fast[ip] = FastOp {
+            fast: Fast::new(&inst),
+            len: inst.len as u32,
+            cycles: inst.command.cycles() as u32,
+        };
+        if self.instructions[ip].replace(inst).is_none() {
+            self.cached += 1;
+        }
+    }
+    //drops every instruction overlapping the written words
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        //nothing cached starts at or after the end of the table
+        let end = self.instructions.len().min(range.end);
+        for ip in range.start.saturating_sub(self.max_len - 1)..end {
+            if matches!(&self.instructions[ip], Some(inst) if ip + inst.len > range.start) {
+                self.instructions[ip] = None;
+                self.fast[ip] = SLOW;
+                self.cached -= 1;
+            }
+        }
+    }
+    pub fn clear(&mut self) {
+        self.instructions.clear();
+        self.fast.clear();
+        self.cached = 0;
+    }
+    pub fn len(&self) -> usize {
+        self.cached
+    }
+}
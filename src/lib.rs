@@ -0,0 +1,17 @@
+//The micro-16 as a library, for embedding it in other programs. MachineBuilder in config
+//builds a Machine, devices and host fns plug in through vm::Machine.
+pub mod backtrace;
+pub mod bench;
+pub mod config;
+#[cfg(test)]
+mod conformance;
+mod coroutine;
+pub mod devices;
+pub mod error;
+mod executable;
+mod icache;
+pub mod memmap;
+pub mod test;
+pub mod threaded;
+mod util;
+pub mod vm;
@@ -1,14 +1,25 @@
-mod devices;
-mod executable;
-mod util;
-mod vm;
-use crate::devices::disk::{Disk};
-use crate::executable::{Bytecode};
-use crate::vm::{CommandType};
-
-mod test;
-use test::run_cases;
+use micro_16::bench;
+use micro_16::config::{MachineBuilder, MachineConfig};
+use micro_16::test::run_cases;
 fn main() {
-    run_cases();
+    if std::env::args().nth(1).as_deref() == Some("bench") {
+        bench::run_bench();
+        return;
+    }
+    let args: Vec<String> = std::env::args().collect();
+    let config = match args.iter().position(|arg| arg == "--config") {
+        Some(i) => {
+            let path = args.get(i + 1).expect("--config needs a path");
+            match MachineConfig::load(path) {
+                Ok(config) => config,
+                Err(err) => {
+                    println!("{}", err);
+                    return;
+                }
+            }
+        }
+        None => MachineConfig::default(),
+    };
+    run_cases(&MachineBuilder::from_config(config));
     //println!("{:?}");
 }
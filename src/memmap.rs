@@ -0,0 +1,206 @@
+use crate::error::{Access, VmError};
+use std::cell::Cell;
+use std::fmt;
+use std::ops::{BitOr, Range};
+//size of the loader region at the bottom of memory
+pub const LOADER_LEN: usize = 512;
+//size of the MMIO window at the top of main memory
+pub const MMIO_LEN: usize = 4096;
+//registers each device slot gets in the window, device n starts at n * MMIO_SLOT_LEN
+pub const MMIO_SLOT_LEN: usize = 256;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RegionKind {
+    Loader,
+    Code,
+    Constants,
+    Heap,
+    Stack,
+    Mmio,
+}
+impl RegionKind {
+    pub fn from_id(id: i16) -> Option<RegionKind> {
+        match id {
+            0 => Some(RegionKind::Loader),
+            1 => Some(RegionKind::Code),
+            2 => Some(RegionKind::Constants),
+            3 => Some(RegionKind::Heap),
+            4 => Some(RegionKind::Stack),
+            5 => Some(RegionKind::Mmio),
+            _ => None,
+        }
+    }
+    pub fn id(&self) -> i16 {
+        match self {
+            RegionKind::Loader => 0,
+            RegionKind::Code => 1,
+            RegionKind::Constants => 2,
+            RegionKind::Heap => 3,
+            RegionKind::Stack => 4,
+            RegionKind::Mmio => 5,
+        }
+    }
+}
+//rwx bits, packed the same way MapRegion takes them (r=1,w=2,x=4)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Perms(u8);
+impl Perms {
+    pub const R: Perms = Perms(1);
+    pub const W: Perms = Perms(2);
+    pub const X: Perms = Perms(4);
+    pub const RW: Perms = Perms(3);
+    pub const RX: Perms = Perms(5);
+    pub const RWX: Perms = Perms(7);
+    pub fn from_bits(bits: i16) -> Perms {
+        Perms((bits & 7) as u8)
+    }
+    pub fn bits(&self) -> i16 {
+        self.0 as i16
+    }
+    pub fn allows(&self, access: Access) -> bool {
+        let bit = match access {
+            Access::Read => Perms::R,
+            Access::Write => Perms::W,
+            Access::Execute => Perms::X,
+        };
+        self.0 & bit.0 != 0
+    }
+}
+impl BitOr for Perms {
+    type Output = Perms;
+    fn bitor(self, rhs: Perms) -> Perms {
+        Perms(self.0 | rhs.0)
+    }
+}
+impl fmt::Display for Perms {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}{}{}",
+            if self.allows(Access::Read) { "r" } else { "-" },
+            if self.allows(Access::Write) { "w" } else { "-" },
+            if self.allows(Access::Execute) { "x" } else { "-" }
+        )
+    }
+}
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub range: Range<usize>,
+    pub perms: Perms,
+}
+impl Region {
+    pub fn new(kind: RegionKind, range: Range<usize>, perms: Perms) -> Region {
+        Region { kind, range, perms }
+    }
+}
+#[derive(Debug, Clone, Default)]
+pub struct MemoryMap {
+    regions: Vec<Region>,
+    //the regions flattened into sorted, non-overlapping spans, each with the index of the
+    //region that wins there. Rebuilt by map so lookups are a binary search.
+    spans: Vec<(Range<usize>, usize)>,
+    //(start, end, allowed) of the span each kind of access hit last. Fetches stay in the code
+    //span and data accesses mostly in one or two, so checks rarely get as far as the search.
+    last: [Cell<(usize, usize, bool)>; 3],
+}
+impl MemoryMap {
+    pub fn new() -> MemoryMap {
+        MemoryMap::default()
+    }
+    //Map present before the loader runs: the loader may rewrite itself, everything
+    //else is plain data until the loader maps the executable's code
+    pub fn boot(max_size: usize) -> MemoryMap {
+        let mut map = MemoryMap::new();
+        map.map(Region::new(RegionKind::Loader, 0..LOADER_LEN, Perms::RWX));
+        map.map(Region::new(
+            RegionKind::Heap,
+            LOADER_LEN..max_size - MMIO_LEN,
+            Perms::RW,
+        ));
+        map.map(Region::new(
+            RegionKind::Mmio,
+            max_size - MMIO_LEN..max_size,
+            Perms::RW,
+        ));
+        map.map(Region::new(RegionKind::Stack, max_size..usize::MAX, Perms::RW));
+        map
+    }
+    //later mappings shadow earlier ones where they overlap
+    pub fn map(&mut self, region: Region) {
+        self.regions.push(region);
+        self.last = Default::default();
+        let mut bounds: Vec<usize> = self
+            .regions
+            .iter()
+            .flat_map(|r| [r.range.start, r.range.end])
+            .collect();
+        bounds.sort_unstable();
+        bounds.dedup();
+        self.spans.clear();
+        for bound in bounds.windows(2) {
+            let (start, end) = (bound[0], bound[1]);
+            let Some(top) = self.regions.iter().rposition(|r| r.range.contains(&start)) else {
+                continue;
+            };
+            match self.spans.last_mut() {
+                Some((span, i)) if *i == top && span.end == start => span.end = end,
+                _ => self.spans.push((start..end, top)),
+            }
+        }
+    }
+    fn span(&self, addr: usize) -> Option<usize> {
+        let at = self.spans.partition_point(|(span, _)| span.end <= addr);
+        self.spans
+            .get(at)
+            .is_some_and(|(span, _)| span.start <= addr)
+            .then_some(at)
+    }
+    pub fn find(&self, addr: usize) -> Option<&Region> {
+        self.span(addr).map(|at| &self.regions[self.spans[at].1])
+    }
+    //the hot path of every memory access, check says why when it's false
+    pub fn allows(&self, addr: usize, access: Access) -> bool {
+        let last = &self.last[access as usize];
+        let (start, end, allowed) = last.get();
+        if start <= addr && addr < end {
+            return allowed;
+        }
+        let Some(at) = self.span(addr) else {
+            return false;
+        };
+        let (span, region) = &self.spans[at];
+        let allowed = self.regions[*region].perms.allows(access);
+        last.set((span.start, span.end, allowed));
+        allowed
+    }
+    pub fn check(&self, addr: usize, access: Access) -> Result<&Region, VmError> {
+        match self.find(addr) {
+            Some(region) if region.perms.allows(access) => Ok(region),
+            Some(region) => Err(VmError::AccessFault {
+                addr,
+                access,
+                region: region.kind,
+                perms: region.perms,
+            }),
+            None => Err(VmError::Unmapped { addr, access }),
+        }
+    }
+    //Checks a whole range a span at a time rather than word by word. A fault reports the
+    //first word that isn't allowed.
+    pub fn check_range(&self, range: Range<usize>, access: Access) -> Result<(), VmError> {
+        let mut addr = range.start;
+        while addr < range.end {
+            self.check(addr, access)?;
+            addr = self.span(addr).map_or(range.end, |at| self.spans[at].0.end);
+        }
+        Ok(())
+    }
+    pub fn dump(&self) {
+        for region in &self.regions {
+            println!(
+                "%{:07}..%{:07} {} {:?}",
+                region.range.start, region.range.end, region.perms, region.kind
+            );
+        }
+    }
+}
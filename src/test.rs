@@ -1,3 +1,4 @@
+use crate::config::MachineBuilder;
 use crate::devices::audio::load_wav;
 use crate::devices::disk::{Disk, DiskSection, DiskSectionType};
 use crate::executable::{Bytecode, Data, Executable, Fn, Library};
@@ -22,10 +23,16 @@ impl TestCase {
         TestCase { name, ttype }
     }
 }
-pub fn run_cases() {
+pub fn run_cases(builder: &MachineBuilder) {
     for case in get_cases() {
         println!("Executing test {}", case.name);
-        let mut machine = Machine::new(false);
+        let mut machine = match builder.clone().build() {
+            Ok(machine) => machine,
+            Err(err) => {
+                println!("{}", err);
+                return;
+            }
+        };
         match case.ttype {
             TestType::External(exe) => {
                 let mut disk: Disk = vec![DiskSection {
@@ -64,25 +71,22 @@ fn stack_case(machine: &mut Machine) {
         .core
         .stack
         .push(DataType::Float(1024.0), &mut machine.core.srp);
+    let base = machine.memory.stack_base();
     let mut byte = Vec::new();
     for i in 0..5 {
-        byte.push(machine.memory.read(4 * 1024 * 1024 + i, machine));
+        byte.push(machine.memory.read(base + i, machine));
     }
     dbg!(&byte);
-    machine.memory.write_range(
-        4 * 1024 * 1024..4 * 1024 * 1024 + 2,
-        vec![1, 2],
-        &mut machine.core,
-    );
-    machine.memory.write_range(
-        4 * 1024 * 1024 + 3..4 * 1024 * 1024 + 5,
-        convert_float(96.0),
-        &mut machine.core,
-    );
+    machine
+        .memory
+        .write_range(base..base + 2, vec![1, 2], &mut machine.core);
+    machine
+        .memory
+        .write_range(base + 3..base + 5, convert_float(96.0), &mut machine.core);
     byte.clear();
     let mut byte = Vec::new();
     for i in 0..5 {
-        byte.push(machine.memory.read(4 * 1024 * 1024 + i, machine));
+        byte.push(machine.memory.read(base + i, machine));
     }
     dbg!(&byte);
 }
@@ -142,7 +146,7 @@ fn gfx_case() -> TestCase {
             Bytecode::Command(AddEx),
             Bytecode::Register(ARP),
             Bytecode::Symbol("controls".to_string(), 0),
-            Bytecode::Command(Push),
+            Bytecode::Command(PushEx),
             Bytecode::Register(EX1),
             Bytecode::Command(IO),
             Bytecode::Int(3),
@@ -154,17 +158,17 @@ fn gfx_case() -> TestCase {
     );
     main_fn.add_block(
         vec![
-            Bytecode::Command(Push),
+            Bytecode::Command(PushEx),
             Bytecode::ConstantLoc(atlas),
             Bytecode::Command(IO),
             Bytecode::Int(3),
             Bytecode::Int(0),
-            Bytecode::Command(Push),
+            Bytecode::Command(PushEx),
             Bytecode::ConstantLoc(layer),
             Bytecode::Command(IO),
             Bytecode::Int(3),
             Bytecode::Int(1),
-            Bytecode::Command(Push),
+            Bytecode::Command(PushEx),
             Bytecode::ConstantLoc(sprite),
             Bytecode::Command(IO),
             Bytecode::Int(3),
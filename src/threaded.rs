@@ -0,0 +1,305 @@
+use crate::devices::flush_mmio;
+use crate::icache::{Instruction, Operand};
+use crate::util::set_reg;
+use crate::vm::{CommandType, DataType, Machine, as_i32, decode_with, exec_bytecode, execute};
+use std::fmt;
+use std::ops::Range;
+use std::rc::Rc;
+//blocks are cut after this many instructions even without a branch
+const MAX_BLOCK_OPS: usize = 64;
+const IP: i16 = 7;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    //decode (or look up) and dispatch one instruction at a time
+    Interpreter,
+    //run whole basic blocks as chains of pre-built closures
+    Threaded,
+}
+type Op = Box<dyn Fn(&mut Machine)>;
+pub struct Block {
+    //each op with its cycle cost
+    ops: Vec<(Op, u64)>,
+    //length in words
+    len: usize,
+}
+//Compiled blocks keyed by their first IP. A write into a compiled block drops it and marks its
+//words as self-modifying, those are left to the interpreter from then on.
+pub struct BlockCache {
+    blocks: Vec<Option<Rc<Block>>>,
+    self_modified: Vec<bool>,
+    max_len: usize,
+    //bumped whenever a block is dropped, so a running block can tell it went stale
+    generation: u64,
+}
+impl fmt::Debug for BlockCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BlockCache")
+            .field("blocks", &self.blocks.iter().flatten().count())
+            .field("generation", &self.generation)
+            .finish()
+    }
+}
+impl Default for BlockCache {
+    fn default() -> BlockCache {
+        BlockCache::new()
+    }
+}
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: vec![],
+            self_modified: vec![],
+            max_len: 1,
+            generation: 0,
+        }
+    }
+    fn get(&self, ip: usize) -> Option<Rc<Block>> {
+        self.blocks.get(ip).and_then(|b| b.clone())
+    }
+    fn insert(&mut self, ip: usize, block: Block) -> Rc<Block> {
+        if self.blocks.len() <= ip {
+            self.blocks.resize(ip + 1, None);
+        }
+        self.max_len = self.max_len.max(block.len);
+        let block = Rc::new(block);
+        self.blocks[ip] = Some(block.clone());
+        block
+    }
+    fn is_self_modified(&self, ip: usize) -> bool {
+        self.self_modified.get(ip).copied().unwrap_or(false)
+    }
+    //drops every block overlapping the written words
+    pub fn invalidate(&mut self, range: Range<usize>) {
+        //nothing compiled starts at or after the end of the table
+        let end = self.blocks.len().min(range.end);
+        for ip in range.start.saturating_sub(self.max_len - 1)..end {
+            let len = match &self.blocks[ip] {
+                Some(block) if ip + block.len > range.start => block.len,
+                _ => continue,
+            };
+            self.blocks[ip] = None;
+            if self.self_modified.len() < ip + len {
+                self.self_modified.resize(ip + len, false);
+            }
+            self.self_modified[ip..ip + len].fill(true);
+            self.generation += 1;
+        }
+    }
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+        self.self_modified.clear();
+        self.generation += 1;
+    }
+}
+//Runs the block at IP, or a single interpreted instruction where no block can be built
+pub fn exec_block(machine: &mut Machine) {
+    let ip = machine.core.ip;
+    let block = match machine.memory.blocks.get(ip) {
+        Some(block) => block,
+        None => match compile_block(machine, ip) {
+            Some(block) => machine.memory.blocks.insert(ip, block),
+            None => {
+                machine.freq.0 += 1;
+                exec_bytecode(machine);
+                return;
+            }
+        },
+    };
+    let generation = machine.memory.blocks.generation;
+    for (op, cycles) in &block.ops {
+        machine.freq.0 += 1;
+        machine.core.cycles += cycles;
+        op(machine);
+        //the block just overwrote part of itself, the rest has to be decoded again
+        if machine.memory.blocks.generation != generation {
+            return;
+        }
+        //let run() switch cores or throttle at the same instruction the interpreter would
+        if machine.core.cycles >= machine.next_event {
+            return;
+        }
+    }
+}
+fn compile_block(machine: &Machine, start: usize) -> Option<Block> {
+    let memory = &machine.memory;
+    let mut ops: Vec<(Op, u64)> = vec![];
+    let mut ip = start;
+    while !memory.blocks.is_self_modified(ip) {
+        //stop at the first word we couldn't execute, the interpreter raises the fault if we get there
+        let inst = match decode_with(ip, |addr| memory.try_fetch(addr, machine)) {
+            Ok(inst) => inst,
+            Err(_) => break,
+        };
+        ops.push((compile_op(inst, ip), inst.command.cycles()));
+        ip += inst.len;
+        if ends_block(&inst) || ops.len() == MAX_BLOCK_OPS {
+            break;
+        }
+    }
+    if ops.is_empty() {
+        None
+    } else {
+        Some(Block {
+            ops,
+            len: ip - start,
+        })
+    }
+}
+fn ends_block(inst: &Instruction) -> bool {
+    match inst.command {
+        CommandType::Jump
+        | CommandType::JumpZero
+        | CommandType::JumpNotZero
+        | CommandType::JumpIndirect
+        | CommandType::Switch
+        | CommandType::Call
+        | CommandType::CallIndirect
+        | CommandType::Return
+        | CommandType::Ret
+        | CommandType::Exit
+        | CommandType::Wait
+        | CommandType::CoResume
+        | CommandType::CoYield
+        | CommandType::IO
+        | CommandType::MapRegion => true,
+        _ => inst.command.operand_counts().1 == 1 && inst.register == IP,
+    }
+}
+fn reads_ip(inst: &Instruction) -> bool {
+    inst.operands[..inst.operand_count]
+        .iter()
+        .any(|op| matches!(op, Operand::Register(IP) | Operand::RegOffset(IP, _)))
+}
+//Builds the closure for one instruction. Common commands get their own closure with the
+//operands baked in, everything else goes through the interpreter's execute.
+fn compile_op(inst: Instruction, ip: usize) -> Op {
+    let next = ip + inst.len;
+    let [a, b, ..] = inst.operands;
+    let reg = inst.register;
+    if reads_ip(&inst) {
+        return Box::new(move |m| execute(m, ip, &inst));
+    }
+    match inst.command {
+        CommandType::Add => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) + b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Sub => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) - b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Mul => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) * b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Div => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) / b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Mod => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) % b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Addf => Box::new(move |m| {
+            m.core.f1 = (a.eval(&m.core) + b.eval(&m.core)) as f32;
+            m.core.ip = next;
+        }),
+        CommandType::Subf => Box::new(move |m| {
+            m.core.f1 = (a.eval(&m.core) - b.eval(&m.core)) as f32;
+            m.core.ip = next;
+        }),
+        CommandType::Mulf => Box::new(move |m| {
+            m.core.f1 = (a.eval(&m.core) * b.eval(&m.core)) as f32;
+            m.core.ip = next;
+        }),
+        CommandType::Divf => Box::new(move |m| {
+            m.core.f1 = (a.eval(&m.core) / b.eval(&m.core)) as f32;
+            m.core.ip = next;
+        }),
+        CommandType::AddEx => Box::new(move |m| {
+            let val = as_i32(a.eval(&m.core)).wrapping_add(as_i32(b.eval(&m.core))) as f64;
+            m.core.ip = next;
+            set_reg(10, &mut m.core, val);
+        }),
+        CommandType::SubEx => Box::new(move |m| {
+            let val = as_i32(a.eval(&m.core)).wrapping_sub(as_i32(b.eval(&m.core))) as f64;
+            m.core.ip = next;
+            set_reg(10, &mut m.core, val);
+        }),
+        CommandType::And => Box::new(move |m| {
+            m.core.r1 = a.eval(&m.core) as i16 & b.eval(&m.core) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Or => Box::new(move |m| {
+            m.core.r1 = a.eval(&m.core) as i16 | b.eval(&m.core) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Xor => Box::new(move |m| {
+            m.core.r1 = a.eval(&m.core) as i16 ^ b.eval(&m.core) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Not => Box::new(move |m| {
+            m.core.r1 = !(a.eval(&m.core) as i16);
+            m.core.ip = next;
+        }),
+        CommandType::Greater => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) > b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::LessThan => Box::new(move |m| {
+            m.core.r1 = (a.eval(&m.core) < b.eval(&m.core)) as i16;
+            m.core.ip = next;
+        }),
+        CommandType::Mov => Box::new(move |m| {
+            let val = a.eval(&m.core);
+            m.core.ip = next;
+            set_reg(reg, &mut m.core, val);
+        }),
+        CommandType::Load => Box::new(move |m| {
+            let addr = a.eval(&m.core) as usize;
+            m.core.ip = next;
+            let val = m.memory.read(addr, m) as f64;
+            set_reg(reg, &mut m.core, val);
+        }),
+        CommandType::Store => Box::new(move |m| {
+            let addr = a.eval(&m.core) as usize;
+            let val = b.eval(&m.core) as i16;
+            m.core.ip = next;
+            m.memory.write(addr, val, &mut m.core);
+            if !m.memory.mmio_writes.is_empty() {
+                flush_mmio(m);
+            }
+        }),
+        CommandType::Push => Box::new(move |m| {
+            let val = a.eval(&m.core) as i16;
+            m.core.ip = next;
+            m.inst_ip = ip;
+            m.core.stack.push(DataType::Int(val), &mut m.core.srp);
+        }),
+        CommandType::Pop => Box::new(move |m| {
+            m.core.ip = next;
+            m.inst_ip = ip;
+            let val = m.core.stack.pop(&mut m.core.srp) as f64;
+            set_reg(reg, &mut m.core, val);
+        }),
+        CommandType::Jump => Box::new(move |m| {
+            m.core.ip = a.eval(&m.core) as usize;
+        }),
+        CommandType::JumpZero => Box::new(move |m| {
+            m.core.ip = if b.eval(&m.core) == 0.0 {
+                a.eval(&m.core) as usize
+            } else {
+                next
+            };
+        }),
+        CommandType::JumpNotZero => Box::new(move |m| {
+            m.core.ip = if b.eval(&m.core) != 0.0 {
+                a.eval(&m.core) as usize
+            } else {
+                next
+            };
+        }),
+        CommandType::NOP => Box::new(move |m| m.core.ip = next),
+        _ => Box::new(move |m| execute(m, ip, &inst)),
+    }
+}
@@ -1,5 +1,5 @@
 use crate::devices::gfx::{Matrix, Point};
-use crate::vm::{CommandType, Core, unpack_dt};
+use crate::vm::{CommandType, Core};
 use byteorder::{ByteOrder, LittleEndian};
 
 pub fn resize_vec<T>(len: usize, vec: &mut Vec<T>, fill: T)
@@ -92,10 +92,11 @@ pub fn convert_float(f: f32) -> Vec<i16> {
         LittleEndian::read_i16(&native[2..4]),
     ]
 }
+//pops `bytes` i16 words, top of the stack first
 pub fn pop_stack(machine: &mut Core, bytes: i32) -> Vec<f64> {
     let mut ret = Vec::new();
     for _i in 0..bytes {
-        ret.push(unpack_dt(machine.stack.pop(&mut machine.srp)));
+        ret.push(machine.stack.pop(&mut machine.srp) as f64);
     }
     ret
 }
@@ -142,6 +143,54 @@ pub fn convert_int_to_command(i: i16) -> CommandType {
         44 => CommandType::PushEx,
         45 => CommandType::StoreEx,
         46 => CommandType::Storef,
+        47 => CommandType::MapRegion,
+        48 => CommandType::PopEx,
+        49 => CommandType::Popf,
+        50 => CommandType::MemCopy,
+        51 => CommandType::MemSet,
+        52 => CommandType::MemCmp,
+        53 => CommandType::CallIndirect,
+        54 => CommandType::JumpIndirect,
+        55 => CommandType::Switch,
+        56 => CommandType::Sqrt,
+        57 => CommandType::Sin,
+        58 => CommandType::Cos,
+        59 => CommandType::Atan2,
+        60 => CommandType::Pow,
+        61 => CommandType::Floor,
+        62 => CommandType::Absf,
+        63 => CommandType::Minf,
+        64 => CommandType::Maxf,
+        65 => CommandType::Greaterf,
+        66 => CommandType::LessThanf,
+        67 => CommandType::Equalf,
+        68 => CommandType::IntToFloat,
+        69 => CommandType::FloatToInt,
+        70 => CommandType::Savef,
+        71 => CommandType::Restoref,
+        72 => CommandType::AndEx,
+        73 => CommandType::OrEx,
+        74 => CommandType::XorEx,
+        75 => CommandType::NotEx,
+        76 => CommandType::ModEx,
+        77 => CommandType::ShlEx,
+        78 => CommandType::ShrEx,
+        79 => CommandType::ShrUEx,
+        80 => CommandType::GreaterEx,
+        81 => CommandType::LessThanEx,
+        82 => CommandType::EqualEx,
+        83 => CommandType::Enter,
+        84 => CommandType::Leave,
+        85 => CommandType::Ret,
+        86 => CommandType::Wait,
+        87 => CommandType::Cas,
+        88 => CommandType::FetchAdd,
+        89 => CommandType::StartCore,
+        90 => CommandType::CoCreate,
+        91 => CommandType::CoResume,
+        92 => CommandType::CoYield,
+        93 => CommandType::CoStatus,
+        94 => CommandType::QueryDevice,
         _ => CommandType::NOP,
     }
 }
@@ -185,9 +234,65 @@ pub fn pack_command(c: CommandType) -> i16 {
         CommandType::PushEx => 44,
         CommandType::StoreEx => 45,
         CommandType::Storef => 46,
+        CommandType::MapRegion => 47,
+        CommandType::PopEx => 48,
+        CommandType::Popf => 49,
+        CommandType::MemCopy => 50,
+        CommandType::MemSet => 51,
+        CommandType::MemCmp => 52,
+        CommandType::CallIndirect => 53,
+        CommandType::JumpIndirect => 54,
+        CommandType::Switch => 55,
+        CommandType::Sqrt => 56,
+        CommandType::Sin => 57,
+        CommandType::Cos => 58,
+        CommandType::Atan2 => 59,
+        CommandType::Pow => 60,
+        CommandType::Floor => 61,
+        CommandType::Absf => 62,
+        CommandType::Minf => 63,
+        CommandType::Maxf => 64,
+        CommandType::Greaterf => 65,
+        CommandType::LessThanf => 66,
+        CommandType::Equalf => 67,
+        CommandType::IntToFloat => 68,
+        CommandType::FloatToInt => 69,
+        CommandType::Savef => 70,
+        CommandType::Restoref => 71,
+        CommandType::AndEx => 72,
+        CommandType::OrEx => 73,
+        CommandType::XorEx => 74,
+        CommandType::NotEx => 75,
+        CommandType::ModEx => 76,
+        CommandType::ShlEx => 77,
+        CommandType::ShrEx => 78,
+        CommandType::ShrUEx => 79,
+        CommandType::GreaterEx => 80,
+        CommandType::LessThanEx => 81,
+        CommandType::EqualEx => 82,
+        CommandType::Enter => 83,
+        CommandType::Leave => 84,
+        CommandType::Ret => 85,
+        CommandType::Wait => 86,
+        CommandType::Cas => 87,
+        CommandType::FetchAdd => 88,
+        CommandType::StartCore => 89,
+        CommandType::CoCreate => 90,
+        CommandType::CoResume => 91,
+        CommandType::CoYield => 92,
+        CommandType::CoStatus => 93,
+        CommandType::QueryDevice => 94,
         _ => 0,
     }
 }
+//i16::MIN is the operand escape, so a literal one has to be escaped itself
+pub fn pack_i16(i: i16) -> Vec<i16> {
+    if i == i16::MIN {
+        vec![i16::MIN, i16::MIN]
+    } else {
+        vec![i]
+    }
+}
 pub fn pack_i32(i: i32) -> Vec<i16> {
     let mut base = vec![i16::MIN, 2];
     base.extend_from_slice(&convert_i32_to_i16(i));
@@ -211,10 +316,18 @@ pub fn pack_register(r: CommandType) -> Vec<i16> {
             CommandType::EX2 => 11,
             CommandType::ARP => 12,
             CommandType::R5 => 13,
+            CommandType::CYC => 14,
+            CommandType::CORE => 15,
             _ => 0,
         },
     ]
 }
+//[reg + offset], evaluates to the register's value plus a constant i32
+pub fn pack_reg_offset(r: CommandType, offset: i32) -> Vec<i16> {
+    let mut base = vec![i16::MIN, 3, pack_register(r)[2]];
+    base.extend_from_slice(&convert_i32_to_i16(offset));
+    base
+}
 pub fn convert_i16_to_i32(bytes: &[i16]) -> i32 {
     LittleEndian::read_i32(
         &(bytes
@@ -245,6 +358,8 @@ pub fn get_reg(reg: i16, machine: &Core) -> f64 {
         11 => convert_i16_to_i32(&[machine.r4, machine.r5]) as f64,
         12 => machine.arp as f64,
         13 => machine.r5 as f64,
+        14 => machine.cycles as i32 as f64,
+        15 => machine.id as f64,
         _ => panic!("Invalid register"),
     }
 }
@@ -259,13 +374,14 @@ pub fn set_reg(reg: i16, machine: &mut Core, value: f64) {
         7 => machine.ip = value as usize,
         8 => machine.stack.resize(value as usize, &mut machine.srp),
         9 => machine.srp = value as usize,
+        //EX registers wrap like the i32 ops that write them
         10 => {
-            let bytes = (value as i32).to_le_bytes();
+            let bytes = (value as i64 as i32).to_le_bytes();
             machine.r2 = LittleEndian::read_i16(&bytes[0..2]);
             machine.r3 = LittleEndian::read_i16(&bytes[2..4]);
         }
         11 => {
-            let bytes = (value as i32).to_le_bytes();
+            let bytes = (value as i64 as i32).to_le_bytes();
             machine.r4 = LittleEndian::read_i16(&bytes[0..2]);
             machine.r5 = LittleEndian::read_i16(&bytes[2..4]);
         }
@@ -1,20 +1,120 @@
+use crate::backtrace::{FaultReport, Frame, SymbolMap, backtrace, print_backtrace};
+use crate::config::MachineConfig;
+use crate::coroutine::{self, CoState, Coroutines};
 use crate::devices;
-use crate::devices::disk::Disk;
-use crate::devices::{Device, RawDevice};
+use crate::devices::disk::{Disk, DiskDrive};
+use crate::devices::host::{HostCalls, HostResult};
+use crate::devices::perf::PerfCounters;
+use crate::devices::{DEVICE_TICK, Device, DeviceCell};
+use crate::error::{Access, VmError, fault, install_fault_hook};
+use crate::icache::{
+    Fast, Instruction, InstructionCache, MAX_OPERANDS, Operand, saturate, set_int_reg,
+};
+use crate::memmap::{MMIO_LEN, MemoryMap, Perms, Region, RegionKind};
+use crate::threaded::{Backend, BlockCache, exec_block};
 use crate::util::*;
 use prompted::input;
+use std::any::Any;
+use std::cell::{Ref, RefCell, RefMut};
+use std::collections::VecDeque;
 use std::ops::Range;
 use std::panic;
-use std::time::Instant;
-fn exec_bytecode(machine: &mut Machine) {
-    let byte = convert_int_to_command(take_bytes(machine, 1)[0] as i16);
+use std::rc::Rc;
+use std::thread;
+use std::time::{Duration, Instant};
+//The interpreter's counterpart to exec_block: runs cached instructions through their fast forms
+//back to back until run() has an event to look at, the first one without a fast form goes
+//through exec_bytecode. No debug output, run() only uses it with debug off.
+pub(crate) fn exec_cached(machine: &mut Machine) {
+    loop {
+        let ip = machine.core.ip;
+        let op = machine.memory.icache.get_fast(ip);
+        if matches!(op.fast, Fast::Slow) {
+            machine.freq.0 += 1;
+            exec_bytecode(machine);
+            return;
+        }
+        machine.freq.0 += 1;
+        machine.core.cycles += op.cycles as u64;
+        exec_fast(machine, op.fast, ip + op.len as usize);
+        if machine.core.cycles >= machine.next_event {
+            return;
+        }
+    }
+}
+pub(crate) fn exec_bytecode(machine: &mut Machine) {
+    let ip = machine.core.ip;
+    let inst = match machine.memory.icache.get(ip) {
+        Some(inst) => *inst,
+        None => {
+            let inst = decode(machine, ip);
+            if machine.memory.icache.enabled {
+                machine.memory.icache.insert(ip, inst);
+            }
+            inst
+        }
+    };
+    machine.core.cycles += inst.command.cycles();
+    execute(machine, ip, &inst);
+}
+//Runs a cached instruction's fast form, same as execute minus the debug output
+#[inline(always)]
+fn exec_fast(machine: &mut Machine, fast: Fast, next: usize) {
+    let core = &mut machine.core;
+    core.ip = next;
+    match fast {
+        Fast::Add(a, b) => core.r1 = saturate(a.get(core) as i64 + b.get(core) as i64),
+        Fast::Sub(a, b) => core.r1 = saturate(a.get(core) as i64 - b.get(core) as i64),
+        Fast::Mul(a, b) => core.r1 = saturate(a.get(core) as i64 * b.get(core) as i64),
+        Fast::And(a, b) => core.r1 = saturate(a.get(core) as i64) & saturate(b.get(core) as i64),
+        Fast::Or(a, b) => core.r1 = saturate(a.get(core) as i64) | saturate(b.get(core) as i64),
+        Fast::Xor(a, b) => core.r1 = saturate(a.get(core) as i64) ^ saturate(b.get(core) as i64),
+        Fast::Greater(a, b) => core.r1 = (a.get(core) > b.get(core)) as i16,
+        Fast::LessThan(a, b) => core.r1 = (a.get(core) < b.get(core)) as i16,
+        Fast::Mov(a, reg) => {
+            let val = saturate(a.get(core) as i64);
+            set_int_reg(reg, core, val);
+        }
+        Fast::Load(a, reg) => {
+            //negative addresses clamp to 0 like the f64 to usize cast
+            let addr = a.get(core).max(0) as usize;
+            let val = machine.memory.read(addr, machine);
+            set_int_reg(reg, &mut machine.core, val);
+        }
+        Fast::Store(a, b) => {
+            let (addr, val) = (a.get(core).max(0) as usize, saturate(b.get(core) as i64));
+            machine.memory.write(addr, val, &mut machine.core);
+            if !machine.memory.mmio_writes.is_empty() {
+                devices::flush_mmio(machine);
+            }
+        }
+        Fast::Jump(a) => core.ip = a.get(core).max(0) as usize,
+        Fast::JumpZero(a, b) => {
+            if b.get(core) == 0 {
+                core.ip = a.get(core).max(0) as usize;
+            }
+        }
+        Fast::JumpNotZero(a, b) => {
+            if b.get(core) != 0 {
+                core.ip = a.get(core).max(0) as usize;
+            }
+        }
+        Fast::Nop => {}
+        Fast::Slow => unreachable!("slow instructions go through execute"),
+    }
+}
+pub(crate) fn execute(machine: &mut Machine, ip: usize, inst: &Instruction) {
+    machine.inst_ip = ip;
     if machine.debug {
-        print!("%{:07}: ", machine.core.ip - 1);
+        print!("%{:07}: ", ip);
     }
-    match byte {
+    //operands see IP just past the command, like they did when they were read inline
+    machine.core.ip = ip + 1;
+    let args = inst.eval(&machine.core);
+    machine.core.ip = ip + inst.len;
+    match inst.command {
         CommandType::Add => {
             //add(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] + args[1]) as i16;
             if machine.debug {
                 println!("Add {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -22,7 +122,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Sub => {
             //sub(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] - args[1]) as i16;
             if machine.debug {
                 println!("Sub {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -30,16 +129,15 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Mul => {
             //mul(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] * args[1]) as i16;
             if machine.debug {
                 println!("Mul {} {} -> {}", args[0], args[1], machine.core.r1);
             }
         }
         CommandType::AddEx => {
-            //addEx(i32,i32) -> ex1
-            let args = take_bytes(machine, 2);
-            set_reg(10, &mut machine.core, (args[0] + args[1]) as f64);
+            //addEx(i32,i32) -> ex1, the i32 family wraps
+            let val = as_i32(args[0]).wrapping_add(as_i32(args[1]));
+            set_reg(10, &mut machine.core, val as f64);
             if machine.debug {
                 println!(
                     "AddEx {} {} -> {}",
@@ -51,8 +149,8 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::SubEx => {
             //subEx(i32,i32) -> ex1
-            let args = take_bytes(machine, 2);
-            set_reg(10, &mut machine.core, (args[0] - args[1]) as f64);
+            let val = as_i32(args[0]).wrapping_sub(as_i32(args[1]));
+            set_reg(10, &mut machine.core, val as f64);
             if machine.debug {
                 println!(
                     "SubEx {} {} -> {}",
@@ -64,8 +162,8 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::MulEx => {
             //mulEx(i32,i32) -> ex1
-            let args = take_bytes(machine, 2);
-            set_reg(10, &mut machine.core, (args[0] * args[1]) as f64);
+            let val = as_i32(args[0]).wrapping_mul(as_i32(args[1]));
+            set_reg(10, &mut machine.core, val as f64);
             if machine.debug {
                 println!(
                     "MulEx {} {} -> {}",
@@ -76,9 +174,13 @@ fn exec_bytecode(machine: &mut Machine) {
             }
         }
         CommandType::DivEx => {
-            //divEx(i32,i32) -> ex1
-            let args = take_bytes(machine, 2);
-            set_reg(10, &mut machine.core, (args[0] / args[1]) as f64);
+            //divEx(i32,i32) -> ex1, faults when dividing by zero
+            let divisor = as_i32(args[1]);
+            if divisor == 0 {
+                fault(VmError::DivideByZero { ip });
+            }
+            let val = as_i32(args[0]).wrapping_div(divisor);
+            set_reg(10, &mut machine.core, val as f64);
             if machine.debug {
                 println!(
                     "DivEx {} {} -> {}",
@@ -88,9 +190,128 @@ fn exec_bytecode(machine: &mut Machine) {
                 );
             }
         }
+        CommandType::AndEx => {
+            //andEx(i32,i32) -> ex1
+            set_reg(10, &mut machine.core, (as_i32(args[0]) & as_i32(args[1])) as f64);
+            if machine.debug {
+                println!(
+                    "AndEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::OrEx => {
+            //orEx(i32,i32) -> ex1
+            set_reg(10, &mut machine.core, (as_i32(args[0]) | as_i32(args[1])) as f64);
+            if machine.debug {
+                println!(
+                    "OrEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::XorEx => {
+            //xorEx(i32,i32) -> ex1
+            set_reg(10, &mut machine.core, (as_i32(args[0]) ^ as_i32(args[1])) as f64);
+            if machine.debug {
+                println!(
+                    "XorEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::NotEx => {
+            //notEx(i32) -> ex1
+            set_reg(10, &mut machine.core, !as_i32(args[0]) as f64);
+            if machine.debug {
+                println!("NotEx {} -> {}", args[0], get_reg(10, &machine.core));
+            }
+        }
+        CommandType::ModEx => {
+            //modEx(i32,i32) -> ex1, faults when dividing by zero like DivEx
+            let divisor = as_i32(args[1]);
+            if divisor == 0 {
+                fault(VmError::DivideByZero { ip });
+            }
+            let val = as_i32(args[0]).wrapping_rem(divisor);
+            set_reg(10, &mut machine.core, val as f64);
+            if machine.debug {
+                println!(
+                    "ModEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::ShlEx => {
+            //shlEx(i32,amount) -> ex1, amount is taken mod 32
+            let val = as_i32(args[0]).wrapping_shl(as_i32(args[1]) as u32);
+            set_reg(10, &mut machine.core, val as f64);
+            if machine.debug {
+                println!(
+                    "ShlEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::ShrEx => {
+            //shrEx(i32,amount) -> ex1, arithmetic
+            let val = as_i32(args[0]).wrapping_shr(as_i32(args[1]) as u32);
+            set_reg(10, &mut machine.core, val as f64);
+            if machine.debug {
+                println!(
+                    "ShrEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::ShrUEx => {
+            //shrUEx(i32,amount) -> ex1, logical
+            let val = (as_i32(args[0]) as u32).wrapping_shr(as_i32(args[1]) as u32) as i32;
+            set_reg(10, &mut machine.core, val as f64);
+            if machine.debug {
+                println!(
+                    "ShrUEx {} {} -> {}",
+                    args[0],
+                    args[1],
+                    get_reg(10, &machine.core)
+                );
+            }
+        }
+        CommandType::GreaterEx => {
+            //greaterEx(i32,i32) -> r1
+            machine.core.r1 = (as_i32(args[0]) > as_i32(args[1])) as i16;
+            if machine.debug {
+                println!("GreaterEx {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
+        CommandType::LessThanEx => {
+            //lessThanEx(i32,i32) -> r1
+            machine.core.r1 = (as_i32(args[0]) < as_i32(args[1])) as i16;
+            if machine.debug {
+                println!("LessThanEx {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
+        CommandType::EqualEx => {
+            //equalEx(i32,i32) -> r1
+            machine.core.r1 = (as_i32(args[0]) == as_i32(args[1])) as i16;
+            if machine.debug {
+                println!("EqualEx {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
         CommandType::Div => {
             //div(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] / args[1]) as i16;
             if machine.debug {
                 println!("Div {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -98,7 +319,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Greater => {
             //greater(f64,f64) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] > args[1]) as i16;
             if machine.debug {
                 println!("GreaterThan {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -106,7 +326,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Addf => {
             //addf(f32,f32) -> f1
-            let args = take_bytes(machine, 2);
             machine.core.f1 = (args[0] + args[1]) as f32;
             if machine.debug {
                 println!("Addf {} {} -> {}", args[0], args[1], machine.core.f1);
@@ -114,7 +333,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Subf => {
             //subf(f32,f32) -> f1
-            let args = take_bytes(machine, 2);
             machine.core.f1 = (args[0] - args[1]) as f32;
             if machine.debug {
                 println!("Subf {} {} -> {}", args[0], args[1], machine.core.f1);
@@ -122,7 +340,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Mulf => {
             //mulf(f32,f32) -> f1
-            let args = take_bytes(machine, 2);
             machine.core.f1 = (args[0] * args[1]) as f32;
             if machine.debug {
                 println!("Mulf {} {} -> {}", args[0], args[1], machine.core.f1);
@@ -130,15 +347,148 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Divf => {
             //divf(f32,f32) -> f1
-            let args = take_bytes(machine, 2);
             machine.core.f1 = (args[0] / args[1]) as f32;
             if machine.debug {
                 println!("Divf {} {} -> {}", args[0], args[1], machine.core.f1);
             }
         }
+        CommandType::Sqrt => {
+            //sqrt(f32) -> f1
+            machine.core.f1 = (args[0] as f32).sqrt();
+            if machine.debug {
+                println!("Sqrt {} -> {}", args[0], machine.core.f1);
+            }
+        }
+        CommandType::Sin => {
+            //sin(f32) -> f1, radians
+            machine.core.f1 = (args[0] as f32).sin();
+            if machine.debug {
+                println!("Sin {} -> {}", args[0], machine.core.f1);
+            }
+        }
+        CommandType::Cos => {
+            //cos(f32) -> f1, radians
+            machine.core.f1 = (args[0] as f32).cos();
+            if machine.debug {
+                println!("Cos {} -> {}", args[0], machine.core.f1);
+            }
+        }
+        CommandType::Atan2 => {
+            //atan2(y,x) -> f1
+            machine.core.f1 = (args[0] as f32).atan2(args[1] as f32);
+            if machine.debug {
+                println!("Atan2 {} {} -> {}", args[0], args[1], machine.core.f1);
+            }
+        }
+        CommandType::Pow => {
+            //pow(f32,f32) -> f1
+            machine.core.f1 = (args[0] as f32).powf(args[1] as f32);
+            if machine.debug {
+                println!("Pow {} {} -> {}", args[0], args[1], machine.core.f1);
+            }
+        }
+        CommandType::Floor => {
+            //floor(f32) -> f1
+            machine.core.f1 = (args[0] as f32).floor();
+            if machine.debug {
+                println!("Floor {} -> {}", args[0], machine.core.f1);
+            }
+        }
+        CommandType::Absf => {
+            //absf(f32) -> f1
+            machine.core.f1 = (args[0] as f32).abs();
+            if machine.debug {
+                println!("Absf {} -> {}", args[0], machine.core.f1);
+            }
+        }
+        CommandType::Minf => {
+            //minf(f32,f32) -> f1
+            machine.core.f1 = (args[0] as f32).min(args[1] as f32);
+            if machine.debug {
+                println!("Minf {} {} -> {}", args[0], args[1], machine.core.f1);
+            }
+        }
+        CommandType::Maxf => {
+            //maxf(f32,f32) -> f1
+            machine.core.f1 = (args[0] as f32).max(args[1] as f32);
+            if machine.debug {
+                println!("Maxf {} {} -> {}", args[0], args[1], machine.core.f1);
+            }
+        }
+        CommandType::Greaterf => {
+            //greaterf(f32,f32) -> r1, compared at f32 precision like f1/f2 hold them
+            machine.core.r1 = (args[0] as f32 > args[1] as f32) as i16;
+            if machine.debug {
+                println!("Greaterf {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
+        CommandType::LessThanf => {
+            //lessThanf(f32,f32) -> r1
+            machine.core.r1 = ((args[0] as f32) < args[1] as f32) as i16;
+            if machine.debug {
+                println!("LessThanf {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
+        CommandType::Equalf => {
+            //equalf(f32,f32) -> r1
+            machine.core.r1 = (args[0] as f32 == args[1] as f32) as i16;
+            if machine.debug {
+                println!("Equalf {} {} -> {}", args[0], args[1], machine.core.r1);
+            }
+        }
+        CommandType::IntToFloat => {
+            //intToFloat(i32) -> Register
+            let reg = inst.register;
+            set_reg(reg, &mut machine.core, (args[0] as i32) as f32 as f64);
+            if machine.debug {
+                println!("IntToFloat {} -> R{}", args[0], reg);
+            }
+        }
+        CommandType::FloatToInt => {
+            //floatToInt(f32) -> Register, truncates toward zero
+            let reg = inst.register;
+            set_reg(reg, &mut machine.core, (args[0] as f32).trunc() as i32 as f64);
+            if machine.debug {
+                println!("FloatToInt {} -> R{}", args[0], reg);
+            }
+        }
+        CommandType::Savef => {
+            //savef(address) <- f1/f2, stores the register's raw bits
+            let reg = inst.register;
+            let f = match reg {
+                5 => machine.core.f1,
+                6 => machine.core.f2,
+                _ => get_reg(reg, &machine.core) as f32,
+            };
+            machine.memory.write_range(
+                args[0] as usize..args[0] as usize + 2,
+                convert_float(f),
+                &mut machine.core,
+            );
+            if machine.debug {
+                println!("Savef R{} -> %{}", reg, args[0]);
+            }
+        }
+        CommandType::Restoref => {
+            //restoref(address) -> f1/f2, loads raw bits back without an f64 round trip
+            let reg = inst.register;
+            let f = unpack_float(
+                &machine
+                    .memory
+                    .read_range(args[0] as usize..args[0] as usize + 2, machine),
+            )
+            .expect("read_range returns 2 words");
+            match reg {
+                5 => machine.core.f1 = f,
+                6 => machine.core.f2 = f,
+                _ => set_reg(reg, &mut machine.core, f as f64),
+            }
+            if machine.debug {
+                println!("Restoref %{} -> R{}", args[0], reg);
+            }
+        }
         CommandType::Mod => {
             //mod(f64,f64) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] % args[1]) as i16;
             if machine.debug {
                 println!("Modulo {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -146,16 +496,33 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Pop => {
             //pop() -> Register
-            let val = unpack_dt(machine.core.stack.pop(&mut machine.core.srp));
-            let reg = take_registers(machine, 1)[0];
+            let val = machine.core.stack.pop(&mut machine.core.srp) as f64;
+            let reg = inst.register;
             set_reg(reg, &mut machine.core, val);
             if machine.debug {
                 println!("Pop {} -> R{}", val, reg);
             }
         }
+        CommandType::PopEx => {
+            //popEx() -> Register
+            let val = machine.core.stack.pop_i32(&mut machine.core.srp) as f64;
+            let reg = inst.register;
+            set_reg(reg, &mut machine.core, val);
+            if machine.debug {
+                println!("PopEx {} -> R{}", val, reg);
+            }
+        }
+        CommandType::Popf => {
+            //popf() -> Register
+            let val = machine.core.stack.pop_f32(&mut machine.core.srp) as f64;
+            let reg = inst.register;
+            set_reg(reg, &mut machine.core, val);
+            if machine.debug {
+                println!("Popf {} -> R{}", val, reg);
+            }
+        }
         CommandType::LessThan => {
             //less_than(f64,f64) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = (args[0] < args[1]) as i16;
             if machine.debug {
                 println!("LessThan {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -163,7 +530,7 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Jump => {
             //jump(address)
-            let addr = take_bytes(machine, 1)[0];
+            let addr = args[0];
             machine.core.ip = addr as usize;
             if machine.debug {
                 println!("Jump {}", addr);
@@ -171,7 +538,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::And => {
             //and(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = args[0] as i16 & args[1] as i16;
             if machine.debug {
                 println!("And {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -179,7 +545,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Or => {
             //or(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = args[0] as i16 | args[1] as i16;
             if machine.debug {
                 println!("Or {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -187,7 +552,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Not => {
             //not(i16) -> r1
-            let args = take_bytes(machine, 1);
             machine.core.r1 = !(args[0] as i16);
             if machine.debug {
                 println!("Not {} -> {}", args[0], machine.core.r1);
@@ -195,7 +559,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Xor => {
             //xor(i16,i16) -> r1
-            let args = take_bytes(machine, 2);
             machine.core.r1 = args[0] as i16 ^ (args[1] as i16);
             if machine.debug {
                 println!("Xor {} {} -> {}", args[0], args[1], machine.core.r1);
@@ -203,7 +566,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Push => {
             //push(i16)
-            let args = take_bytes(machine, 1);
             machine
                 .core
                 .stack
@@ -214,7 +576,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::PushEx => {
             //pushEx(i32)
-            let args = take_bytes(machine, 1);
             machine
                 .core
                 .stack
@@ -225,7 +586,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Pushf => {
             //pushf(f32)
-            let args = take_bytes(machine, 1);
             machine
                 .core
                 .stack
@@ -236,8 +596,7 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Mov => {
             //mov(f64) -> Register
-            let args = take_bytes(machine, 1);
-            let reg = take_registers(machine, 1)[0];
+            let reg = inst.register;
             set_reg(reg, &mut machine.core, args[0]);
             if machine.debug {
                 println!("Mov {} -> R{}", args[0], reg);
@@ -245,7 +604,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::JumpNotZero => {
             //jnz(address,f64)
-            let args = take_bytes(machine, 2);
             if args[1] != 0.0 {
                 machine.core.ip = args[0] as usize;
             }
@@ -255,7 +613,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::JumpZero => {
             //jz(address,f64)
-            let args = take_bytes(machine, 2);
             if args[1] == 0.0 {
                 machine.core.ip = args[0] as usize;
             }
@@ -265,9 +622,8 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Load => {
             //load(address) -> Register
-            let args = take_bytes(machine, 1);
             let val = machine.memory.read(args[0] as usize, machine) as f64;
-            let reg = take_registers(machine, 1)[0];
+            let reg = inst.register;
             set_reg(reg, &mut machine.core, val);
             if machine.debug {
                 println!("Load %{} -> R{}", args[0], reg);
@@ -275,11 +631,10 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::LoadEx => {
             //load(address) -> Register
-            let args = take_bytes(machine, 1);
             let val = machine
                 .memory
                 .read_range(args[0] as usize..args[0] as usize + 2usize, machine);
-            let reg = take_registers(machine, 1)[0];
+            let reg = inst.register;
             set_reg(
                 reg,
                 &mut machine.core,
@@ -291,7 +646,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Store => {
             //store(address,i16)
-            let args = take_bytes(machine, 2);
             machine
                 .memory
                 .write(args[0] as usize, args[1] as i16, &mut machine.core);
@@ -301,9 +655,8 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::StoreEx => {
             //storeEx(address,i32)
-            let args = take_bytes(machine, 2);
             machine.memory.write_range(
-                args[0] as usize..(args[0] + 1.0) as usize,
+                args[0] as usize..args[0] as usize + 2usize,
                 convert_i32_to_i16(args[1] as i32).to_vec(),
                 &mut machine.core,
             );
@@ -313,7 +666,6 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Storef => {
             //storef(address,f32)
-            let args = take_bytes(machine, 2);
             let f = convert_float(args[1] as f32);
             machine.memory.write_range(
                 args[0] as usize..args[0] as usize + f.len(),
@@ -326,20 +678,26 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::Exit => {
             //exit()
-            machine.on = false;
+            //a coroutine finishes, core 0 stops the machine, any other core just halts itself
+            if let Some(handle) = machine.core.coroutine {
+                coroutine::finish(machine, handle);
+            } else if machine.core.id == 0 {
+                machine.on = false;
+            } else {
+                machine.halt_core();
+            }
             if machine.debug {
                 println!("Exit");
             }
         }
         CommandType::Loadf => {
             //loadf(address) -> Register
-            let args = take_bytes(machine, 1);
             let val_bytes = &machine
                 .memory
                 .read_range(args[0] as usize..args[0] as usize + 2usize, machine);
             let val = unpack_float(val_bytes)
                 .expect(&format!("Couldn't get float at memory address {}", args[0]));
-            let reg = take_registers(machine, 1)[0];
+            let reg = inst.register;
             set_reg(reg, &mut machine.core, val as f64);
             if machine.debug {
                 println!("Loadf %{} -> R{}", args[0], reg);
@@ -347,62 +705,278 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         CommandType::IO => {
             //io(device,command), driverags are on stack
-            let args = take_bytes(machine, 2);
             if machine.debug {
                 println!("IO {} {}", args[0], args[1]);
             }
-            (machine.devices[args[0] as usize].driver.clone())(
-                machine,
-                args[1] as i16,
-                args[0] as usize,
-            );
+            machine.perf.io_calls += 1;
+            devices::command(machine, ip, args[0] as i32, args[1] as i16);
         }
+        //Calling convention, stack grows up:
+        //  caller pushes args, the first arg deepest, then Call
+        //  Call pushes prev arp and returnAddr (i32s) and points ARP at prev arp
+        //  Enter(locals) reserves zeroed locals at ARP+4, Fn::build emits it for the symbol table
+        //  args live at ARP-argCount.., locals at ARP+4..
+        //  the callee pushes its return values last and Ret(returned,argCount) unwinds to ARP,
+        //  leaving the returned words where the args were for the caller to pop
         //[callStack]
         //...args
-        //prev arp
+        //prev arp <- ARP
         //returnAddr
         //...vars
         // returnedBytes
         CommandType::Call => {
             //call(fnptr)
-            let func = take_bytes(machine, 1)[0];
-            let arp = machine.core.srp + 4 * 1024 * 1024;
-            machine.core.stack.push(
-                DataType::Int32(machine.core.arp as i32),
-                &mut machine.core.srp,
-            );
-            machine.core.arp = arp;
-            machine.core.stack.push(
-                DataType::Int32(machine.core.ip as i32),
-                &mut machine.core.srp,
-            );
-            machine.core.ip = func as usize;
+            let func = args[0] as usize;
+            call(machine, func);
             if machine.debug {
                 println!("Call %{}", func);
             }
         }
+        CommandType::CallIndirect => {
+            //callIndirect(ptr), calls the i32 address stored at ptr
+            let func = read_pointer(machine, args[0] as usize);
+            call(machine, func);
+            if machine.debug {
+                println!("CallIndirect [%{}] -> %{}", args[0], func);
+            }
+        }
+        CommandType::Switch => {
+            //switch(value,table_ptr,count,default), the table holds count i32 addresses
+            let value = args[0];
+            let addr = if value >= 0.0 && value < args[2] {
+                read_pointer(machine, args[1] as usize + 2 * value as usize)
+            } else {
+                args[3] as usize
+            };
+            machine.core.ip = addr;
+            if machine.debug {
+                println!("Switch {} [%{}; {}] -> %{}", value, args[1], args[2], addr);
+            }
+        }
+        CommandType::JumpIndirect => {
+            //jumpIndirect(ptr), jumps to the i32 address stored at ptr
+            let addr = read_pointer(machine, args[0] as usize);
+            machine.core.ip = addr;
+            if machine.debug {
+                println!("JumpIndirect [%{}] -> %{}", args[0], addr);
+            }
+        }
         CommandType::Return => {
             //return(returned_byte_count,fn_symbol_len,args)
-            let args = take_bytes(machine, 3);
-            machine.core.stack.pop_range(
-                machine.core.srp - args[0] as usize - args[1] as usize
-                    ..machine.core.srp - args[0] as usize,
-                &mut machine.core.srp,
+            let returned = args[0] as usize;
+            let stack = &mut machine.core.stack;
+            let top = stack.below(machine.core.srp, returned);
+            stack.pop_range(stack.below(top, args[1] as usize)..top, &mut machine.core.srp);
+            let top = stack.below(machine.core.srp, returned + 2);
+            machine.core.ip = stack.remove_i32(top, &mut machine.core.srp) as usize;
+            let top = stack.below(machine.core.srp, returned + 2);
+            machine.core.arp = stack.remove_i32(top, &mut machine.core.srp) as usize;
+            let top = stack.below(machine.core.srp, returned);
+            stack.pop_range(stack.below(top, args[2] as usize)..top, &mut machine.core.srp);
+            stack.depth = stack.depth.saturating_sub(1);
+            if machine.debug {
+                println!("Return {} {} {}", args[0], args[1], args[2]);
+            }
+        }
+        CommandType::Enter => {
+            //enter(locals), reserves locals on top of the frame
+            let top = machine.core.srp + args[0] as usize;
+            machine.core.stack.resize(top, &mut machine.core.srp);
+            machine.core.srp = top;
+            if machine.debug {
+                println!("Enter {}", args[0]);
+            }
+        }
+        CommandType::Leave => {
+            //leave(), drops the locals and anything pushed after them
+            let top = frame(machine) + 4;
+            machine.core.stack.resize(top, &mut machine.core.srp);
+            if machine.debug {
+                println!("Leave -> SP {}", top);
+            }
+        }
+        CommandType::Ret => {
+            //ret(returned,args), unwinds from ARP, no need to know how much the callee left behind
+            let returned = args[0] as usize;
+            let frame = frame(machine);
+            let stack = &mut machine.core.stack;
+            let values = stack.read_bytes(stack.below(machine.core.srp, returned), returned);
+            let prev_arp = convert_i16_to_i32(&stack.read_bytes(frame, 2));
+            let ret_ip = convert_i16_to_i32(&stack.read_bytes(frame + 2, 2));
+            stack.resize(stack.below(frame, args[1] as usize), &mut machine.core.srp);
+            for word in values {
+                stack.push(DataType::Int(word), &mut machine.core.srp);
+            }
+            stack.depth = stack.depth.saturating_sub(1);
+            machine.core.arp = prev_arp as usize;
+            machine.core.ip = ret_ip as usize;
+            if machine.debug {
+                println!("Ret {} {} -> %{}", args[0], args[1], ret_ip);
+            }
+        }
+        CommandType::MapRegion => {
+            //mapRegion(start,len,kind,perms), loader only
+            let Some(kind) = RegionKind::from_id(args[2] as i16) else {
+                fault(VmError::InvalidRegionKind {
+                    ip,
+                    kind: args[2] as i16,
+                })
+            };
+            let region = Region::new(
+                kind,
+                args[0] as usize..(args[0] + args[1]) as usize,
+                Perms::from_bits(args[3] as i16),
             );
-            machine.core.ip = unpack_dt(machine.core.stack.remove(
-                machine.core.srp - (args[0] as usize + 1),
-                &mut machine.core.srp,
-            )) as usize;
-            machine.core.arp = unpack_dt(machine.core.stack.remove(
-                machine.core.srp - (args[0] as usize + 1),
-                &mut machine.core.srp,
-            )) as usize;
-            machine.core.stack.pop_range(
-                machine.core.srp - 1 - args[2] as usize..machine.core.srp - 1,
-                &mut machine.core.srp,
+            if machine.debug {
+                println!(
+                    "MapRegion %{}..%{} {:?} {}",
+                    region.range.start, region.range.end, kind, region.perms
+                );
+            }
+            machine.memory.map_region(ip, region);
+        }
+        CommandType::MemCopy => {
+            //memCopy(src,dst,len), overlapping ranges copy like memmove
+            let len = args[2] as usize;
+            machine.core.cycles += len as u64;
+            let (src, dst) = (args[0] as usize, args[1] as usize);
+            let words = machine.memory.read_range(src..src + len, machine);
+            machine
+                .memory
+                .write_range(dst..dst + len, words, &mut machine.core);
+            if machine.debug {
+                println!("MemCopy %{} -> %{} ({} words)", src, dst, len);
+            }
+        }
+        CommandType::MemSet => {
+            //memSet(dst,i16,len)
+            let len = args[2] as usize;
+            machine.core.cycles += len as u64;
+            let dst = args[0] as usize;
+            machine.memory.write_range(
+                dst..dst + len,
+                vec![args[1] as i16; len],
+                &mut machine.core,
             );
             if machine.debug {
-                println!("Return {} {} {}", args[0], args[1], args[2]);
+                println!("MemSet %{} = {} ({} words)", dst, args[1], len);
+            }
+        }
+        CommandType::MemCmp => {
+            //memCmp(a,b,len) -> r1, -1/0/1 comparing words as i16s
+            let len = args[2] as usize;
+            machine.core.cycles += len as u64;
+            let (a, b) = (args[0] as usize, args[1] as usize);
+            let lhs = machine.memory.read_range(a..a + len, machine);
+            let rhs = machine.memory.read_range(b..b + len, machine);
+            machine.core.r1 = lhs.cmp(&rhs) as i16;
+            if machine.debug {
+                println!("MemCmp %{} %{} ({} words) -> {}", a, b, len, machine.core.r1);
+            }
+        }
+        CommandType::Wait => {
+            //wait(), parks the core until the next frame boundary or a device event. Other
+            //cores keep running, the host only sleeps when every core is waiting.
+            let idle = machine.freq.2;
+            machine.wait_core();
+            if machine.debug {
+                println!("Wait {:?}", machine.freq.2 - idle);
+            }
+        }
+        //Cores only switch between instructions, so these are atomic as they stand
+        CommandType::Cas => {
+            //cas(address, expected, new) -> r1
+            //writes new only if the word still holds expected, r1 gets the word it held
+            let addr = args[0] as usize;
+            let old = machine.memory.read(addr, machine);
+            if old == args[1] as i16 {
+                machine.memory.write(addr, args[2] as i16, &mut machine.core);
+            }
+            machine.core.r1 = old;
+            if machine.debug {
+                println!("Cas %{} {} {} -> {}", addr, args[1], args[2], old);
+            }
+        }
+        CommandType::FetchAdd => {
+            //fetchAdd(address, i16) -> r1
+            let addr = args[0] as usize;
+            let old = machine.memory.read(addr, machine);
+            machine
+                .memory
+                .write(addr, old.wrapping_add(args[1] as i16), &mut machine.core);
+            machine.core.r1 = old;
+            if machine.debug {
+                println!("FetchAdd %{} {} -> {}", addr, args[1], old);
+            }
+        }
+        CommandType::StartCore => {
+            //startCore(entry, arg: i32) -> r1
+            //the new core starts at entry with arg on its own stack, r1 gets its id or -1
+            //when MAX_CORES are already running
+            let id = machine.start_core(args[0] as usize, as_i32(args[1]));
+            machine.core.r1 = id.map_or(-1, |id| id as i16);
+            if machine.debug {
+                println!("StartCore %{} {} -> {:?}", args[0], args[1], id);
+            }
+        }
+        //Coroutines run on the core that resumes them until they yield or Exit, see
+        //coroutine.rs. Exit frees the coroutine and leaves 0 in the resumer's r1.
+        CommandType::CoCreate => {
+            //coCreate(entry, arg: i32) -> r1
+            //a suspended coroutine starting at entry with arg on its own stack, r1 gets its
+            //handle or -1 when MAX_COROUTINES are alive
+            let handle = coroutine::create(machine, args[0] as usize, as_i32(args[1]));
+            machine.core.r1 = handle.map_or(-1, |handle| handle as i16);
+            if machine.debug {
+                println!("CoCreate %{} {} -> {:?}", args[0], args[1], handle);
+            }
+        }
+        CommandType::CoResume => {
+            //coResume(handle) -> r1
+            //runs the coroutine until it yields, r1 gets the yielded value
+            if machine.debug {
+                println!("CoResume {}", args[0]);
+            }
+            coroutine::resume(machine, ip, args[0] as usize);
+        }
+        CommandType::CoYield => {
+            //coYield(i16)
+            if machine.debug {
+                println!("CoYield {}", args[0]);
+            }
+            coroutine::yield_value(machine, ip, args[0] as i16);
+        }
+        CommandType::CoStatus => {
+            //coStatus(handle) -> r1
+            //0 for a finished or unknown handle, 1 suspended, 2 running
+            machine.core.r1 = match machine.coroutines.get(args[0] as usize) {
+                None => 0,
+                Some(co) if co.state == CoState::Suspended => 1,
+                Some(_) => 2,
+            };
+            if machine.debug {
+                println!("CoStatus {} -> {}", args[0], machine.core.r1);
+            }
+        }
+        CommandType::QueryDevice => {
+            //queryDevice(device, dest) -> r1
+            //writes the device's kind, version and caps to dest, zeros for an empty slot,
+            //r1 gets the number of slots
+            let slot = usize::try_from(args[0] as i32).ok();
+            let words = match slot.and_then(|id| machine.devices.get(id)) {
+                Some(device) => {
+                    let info = device.borrow().info();
+                    vec![info.kind, info.version, info.caps]
+                }
+                None => vec![0; 3],
+            };
+            let dest = args[1] as usize;
+            machine
+                .memory
+                .write_range(dest..dest + 3, words, &mut machine.core);
+            machine.core.r1 = machine.devices.len() as i16;
+            if machine.debug {
+                println!("QueryDevice {} %{}", args[0], dest);
             }
         }
         CommandType::NOP => {
@@ -413,100 +987,257 @@ fn exec_bytecode(machine: &mut Machine) {
         }
         _ => {}
     }
+    if !machine.memory.mmio_writes.is_empty() {
+        devices::flush_mmio(machine);
+    }
 }
 
-fn take_bytes(machine: &mut Machine, bytecount: i16) -> Vec<f64> {
-    let mut offset = machine.core.ip;
-    let mut real_byte_count = 0;
-    let mut bytes: Vec<f64> = Vec::new();
-    for i in 0..bytecount {
-        let byte = machine.memory.read(offset + i as usize, machine);
+//the documented speed of a micro-16, see CommandType::cycles for what things cost
+pub const DEFAULT_CLOCK_HZ: u64 = 4_000_000;
+const THROTTLE_SLICE: Duration = Duration::from_millis(1);
+const MAX_CLOCK_LAG: Duration = Duration::from_millis(50);
+//wall time and cycle count the throttle measures from, and when it looks next
+#[derive(Debug)]
+struct Throttle {
+    start: Instant,
+    cycles: u64,
+    next_check: u64,
+}
+impl Throttle {
+    fn new() -> Throttle {
+        Throttle {
+            start: Instant::now(),
+            cycles: 0,
+            next_check: 0,
+        }
+    }
+}
+pub const MAX_CORES: usize = 8;
+//cycles a core runs before the next one gets its turn
+const CORE_SLICE: u64 = 256;
+//Cores waiting for their turn, round robin in the order they started. The running core is
+//Machine::core, the cycle counter moves along with it so CYC and the throttle see one clock.
+#[derive(Debug)]
+struct Scheduler {
+    parked: VecDeque<Core>,
+    //cores in Wait and the cycle they wake up at, they go back into parked then
+    waiting: Vec<(Core, u64)>,
+    next_id: usize,
+    //when the running core's turn is up, never with no other core to switch to
+    slice_end: u64,
+}
+impl Scheduler {
+    fn new() -> Scheduler {
+        Scheduler {
+            parked: VecDeque::new(),
+            waiting: vec![],
+            next_id: 1,
+            slice_end: u64::MAX,
+        }
+    }
+}
+//Frames are 1/60s of guest time, counted in cycles from machine start. Unthrottled machines
+//still go by the documented clock so Wait costs the same.
+const FRAMES_PER_SEC: u64 = 60;
+//how long the host sleeps at a time while every core waits, between polls for device events
+const IDLE_SLICE: Duration = Duration::from_millis(1);
+//pushes the frame described above and enters func
+fn call(machine: &mut Machine, func: usize) {
+    let arp = machine.core.srp + machine.memory.stack_base();
+    machine.core.stack.push(
+        DataType::Int32(machine.core.arp as i32),
+        &mut machine.core.srp,
+    );
+    machine.core.arp = arp;
+    machine.core.stack.push(
+        DataType::Int32(machine.core.ip as i32),
+        &mut machine.core.srp,
+    );
+    machine.core.ip = func;
+    machine.core.stack.depth += 1;
+}
+//stack index of the current frame's prev arp word
+fn frame(machine: &Machine) -> usize {
+    let stack = &machine.core.stack;
+    stack.below(machine.core.arp, machine.memory.stack_base())
+}
+//i32 operands wrap instead of saturating, so u32 values like gfx pointers keep their bits
+pub(crate) fn as_i32(value: f64) -> i32 {
+    value as i64 as i32
+}
+//code addresses are stored as i32s, like FunctionRef constants
+fn read_pointer(machine: &Machine, ptr: usize) -> usize {
+    convert_i16_to_i32(&machine.memory.read_range(ptr..ptr + 2, machine)) as usize
+}
+//Reads the instruction at ip, operands are decoded but not evaluated
+fn decode(machine: &Machine, ip: usize) -> Instruction {
+    decode_with(ip, |addr| Ok(machine.memory.fetch(addr, machine))).unwrap_or_else(|err| fault(err))
+}
+//decode over any word source, stops at the first error from fetch or a malformed operand
+pub(crate) fn decode_with(
+    ip: usize,
+    mut fetch: impl FnMut(usize) -> Result<i16, VmError>,
+) -> Result<Instruction, VmError> {
+    let command = convert_int_to_command(fetch(ip)?);
+    let (operand_count, register_count) = command.operand_counts();
+    let mut operands = [Operand::Value(0.0); MAX_OPERANDS];
+    let mut offset = ip + 1;
+    for operand in operands.iter_mut().take(operand_count) {
+        let byte = fetch(offset)?;
         if byte == i16::MIN {
-            match machine.memory.read(offset + i as usize + 1, machine) {
+            match fetch(offset + 1)? {
                 0 => {
-                    bytes.push(
-                        unpack_float(&[
-                            machine.memory.read(offset + 2 + i as usize, machine),
-                            machine.memory.read(offset + 3 + i as usize, machine),
-                        ])
-                        .expect("Couldn't convert bytes from i16 to float")
+                    *operand = Operand::Value(
+                        unpack_float(&[fetch(offset + 2)?, fetch(offset + 3)?])
+                            .expect("Couldn't convert bytes from i16 to float")
                             as f64,
                     );
-                    offset += 3;
-                    real_byte_count += 4;
+                    offset += 4;
                 }
                 1 => {
-                    bytes.push(get_reg(
-                        machine.memory.read(offset + 2 + i as usize, machine),
-                        &machine.core,
-                    ));
-                    offset += 2;
-                    real_byte_count += 3;
+                    *operand = Operand::Register(fetch(offset + 2)?);
+                    offset += 3;
                 }
                 2 => {
-                    bytes.push(convert_i16_to_i32(&[
-                        machine.memory.read(offset + 2 + i as usize, machine),
-                        machine.memory.read(offset + 3 + i as usize, machine),
-                    ]) as f64);
-                    offset += 3;
-                    real_byte_count += 4;
+                    *operand = Operand::Value(
+                        convert_i16_to_i32(&[fetch(offset + 2)?, fetch(offset + 3)?]) as f64,
+                    );
+                    offset += 4;
+                }
+                3 => {
+                    *operand = Operand::RegOffset(
+                        fetch(offset + 2)?,
+                        convert_i16_to_i32(&[fetch(offset + 3)?, fetch(offset + 4)?]) as f64,
+                    );
+                    offset += 5;
+                }
+                //escaped literal i16::MIN
+                i16::MIN => {
+                    *operand = Operand::Value(i16::MIN as f64);
+                    offset += 2;
                 }
-                _ => {}
+                tag => return Err(VmError::InvalidOperand { addr: offset, tag }),
             }
         } else {
-            bytes.push(byte as f64);
-            real_byte_count += 1;
+            *operand = Operand::Value(byte as f64);
+            offset += 1;
         }
     }
-    machine.core.ip += real_byte_count;
-    bytes
-}
-fn take_registers(machine: &mut Machine, count: i16) -> Vec<i16> {
-    let mut bytes: Vec<i16> = Vec::new();
-    for i in 0..count {
-        let byte = machine
-            .memory
-            .read(machine.core.ip + 2 + (i * 3) as usize, machine);
-        bytes.push(byte);
-    }
-    machine.core.ip += (count * 3) as usize;
-    bytes
+    let mut register = 0;
+    if register_count == 1 {
+        register = fetch(offset + 2)?;
+        offset += 3;
+    }
+    Ok(Instruction {
+        command,
+        operands,
+        operand_count,
+        register,
+        len: offset - ip,
+    })
 }
 pub struct Machine {
-    pub devices: Vec<Device>,
+    pub devices: Vec<DeviceCell>,
     pub core: Core,
     pub debug: bool,
     pub memory: Memory,
     pub on: bool,
-    pub freq: (u64, Instant),
+    //(instructions executed, start, time spent in Wait)
+    pub freq: (u64, Instant, Duration),
+    pub fault: Option<VmError>,
+    //guest frames at the time of the fault
+    pub fault_trace: Vec<Frame>,
+    pub backend: Backend,
+    pub symbols: SymbolMap,
+    //target clock in Hz, None runs as fast as the host allows
+    pub clock_hz: Option<u64>,
+    //words each core and coroutine's stack may grow to
+    pub stack_limit: usize,
+    throttle: Throttle,
+    //frames and IO calls for the perf device
+    pub perf: PerfCounters,
+    sched: Scheduler,
+    pub coroutines: Coroutines,
+    next_device_tick: u64,
+    //first cycle count the throttle, scheduler or devices have to look at
+    pub(crate) next_event: u64,
+    //start of the instruction running, stack faults happen below it and report this IP
+    pub(crate) inst_ip: usize,
 }
 impl Machine {
-    pub fn new(debug: bool) -> Machine {
+    //the default hardware without window or audio output, for benchmarks and tests. See
+    //MachineBuilder for anything else.
+    pub fn new_headless(debug: bool) -> Machine {
+        let config = MachineConfig {
+            headless: true,
+            ..MachineConfig::default()
+        };
+        Self::with_config(debug, &config)
+    }
+    //config has to be valid, MachineBuilder::build checks it
+    pub(crate) fn with_config(debug: bool, config: &MachineConfig) -> Machine {
+        install_fault_hook();
         let m = Machine {
-            devices: devices::get_device_list(),
-            core: Core::new(),
+            devices: devices::get_device_list(config),
+            core: Core::new(config.memory, config.stack_limit),
             debug,
             on: true,
-            memory: Memory::new(4 * 1024 * 1024), //4MB max
-            freq: (0, Instant::now()),
+            memory: Memory::new(config.memory),
+            freq: (0, Instant::now(), Duration::ZERO),
+            fault: None,
+            fault_trace: vec![],
+            backend: Backend::Interpreter,
+            symbols: SymbolMap::new(),
+            clock_hz: Some(DEFAULT_CLOCK_HZ),
+            stack_limit: config.stack_limit,
+            throttle: Throttle::new(),
+            perf: PerfCounters::new(),
+            sched: Scheduler::new(),
+            coroutines: Coroutines::new(),
+            next_device_tick: DEVICE_TICK,
+            next_event: 0,
+            inst_ip: 0,
         };
         m
     }
     fn panic(&self, addr: usize) {
-        println!("PANIC at %{}", addr);
+        println!("PANIC at %{} on core {}", addr, self.core.id);
+        match self.fault_report() {
+            Some(report) => print!("{}", report),
+            None => print_backtrace(&self.fault_trace),
+        }
         println!("__________________________________________");
         println!("State:");
         self.dump_state();
         //println!("Memory");
         //println!("{:?}", self.memory.data);
     }
+    //what stopped the last run, if it faulted, along with the guest frames at the time
+    pub fn fault_report(&self) -> Option<FaultReport> {
+        self.fault.clone().map(|error| FaultReport {
+            error,
+            trace: self.fault_trace.clone(),
+        })
+    }
+    //instructions per second of busy time, idle time in Wait doesn't count
+    pub fn mhz(&self) -> f64 {
+        let busy = self.freq.1.elapsed().saturating_sub(self.freq.2);
+        if busy.is_zero() {
+            0.0
+        } else {
+            self.freq.0 as f64 / busy.as_secs_f64() / 1e6
+        }
+    }
     pub fn dump_state(&self) {
-        println!("Core:");
-        println!("IP: {}", self.core.ip);
         println!(
-            "Frequency: {:.6}Mhz",
-            self.freq.0 / (self.freq.1.elapsed().as_secs() + 1)
+            "Core {} ({} more running, {} waiting):",
+            self.core.id,
+            self.sched.parked.len(),
+            self.sched.waiting.len()
         );
+        println!("IP: {}", self.core.ip);
+        println!("Frequency: {:.6}Mhz", self.mhz());
+        println!("Cycles: {}", self.core.cycles);
         println!("Registers:");
         println!("R1: {}", self.core.r1);
         println!("R2: {}", self.core.r2);
@@ -518,6 +1249,7 @@ impl Machine {
         println!("EX1: {}", get_reg(10, &self.core));
         println!("EX2: {}", get_reg(11, &self.core));
         println!("ARP: {}", self.core.arp);
+        println!("Cached instructions: {}", self.memory.icache.len());
         println!("Stack:");
         println!("SRP: {}", self.core.srp);
         println!("Stack Pointer: {}", self.core.stack.len());
@@ -525,19 +1257,16 @@ impl Machine {
         self.core.stack.dump();
     }
     pub fn run(&mut self) {
-        if let RawDevice::Disk(disk) = &mut self.devices[0].contents {
-            if disk[0].data.len() >= 256 {
-                self.memory
-                    .write_range(0..256, disk[0].data[0..256].to_vec(), &mut self.core);
-            } else {
-                self.memory.write_range(
-                    0..disk[0].data.len(),
-                    disk[0].data.clone(),
-                    &mut self.core,
-                );
-            }
-        } else {
-            println!("No Disk Plugged In");
+        for device in &self.devices {
+            device.borrow_mut().reset();
+        }
+        let boot = self.device::<DiskDrive>(0).map(|drive| {
+            let data = &drive.disk[0].data;
+            data[..data.len().min(256)].to_vec()
+        });
+        match boot {
+            Some(boot) => self.memory.write_range(0..boot.len(), boot, &mut self.core),
+            None => println!("No Disk Plugged In"),
         }
         let mut debug_console = true;
         let mut breakpoints = Vec::new();
@@ -562,10 +1291,13 @@ impl Machine {
                                 println!("  debugOff - Exit Debugger");
                                 println!("  goto - Jump to an address");
                                 println!("  stack - Display the stack");
+                                println!("  bt - Display the guest call stack");
+                                println!("  coroutines - List live coroutines");
                                 println!("  exitConsole - Exit debug console");
                                 println!("  breakpoint - Set a breakpoint");
                                 println!("  device - Dump a device");
                                 println!("  registers - Dump registers");
+                                println!("  memoryMap - Display the memory map");
                                 println!("  stop - Stops execution");
                                 println!(
                                     "  nextCommand - Reads the byte at IP and displays it as a command"
@@ -623,6 +1355,12 @@ impl Machine {
                             "stack" => {
                                 println!("{:?}", self.core.stack.data)
                             }
+                            "bt" => {
+                                print_backtrace(&backtrace(self, false));
+                            }
+                            "coroutines" => {
+                                coroutine::dump_coroutines(self);
+                            }
                             "exitConsole" => {
                                 debug_console = false;
                                 self.freq.0 += 1;
@@ -638,7 +1376,18 @@ impl Machine {
                             "device" => {
                                 let device =
                                     command[1].parse::<usize>().expect("Invalid device ID");
-                                println!("{:?}", self.devices[device].contents)
+                                let device = self.devices[device].borrow();
+                                println!("{}", device.debug_dump());
+                                println!("Snapshot: {:?}", device.snapshot());
+                            }
+                            "devices" => {
+                                for (id, device) in self.devices.iter().enumerate() {
+                                    let info = device.borrow().info();
+                                    println!(
+                                        "  {}: kind {} v{} caps {:#x}",
+                                        id, info.kind, info.version, info.caps
+                                    );
+                                }
                             }
                             "registers" => {
                                 println!(
@@ -658,6 +1407,9 @@ impl Machine {
                                     self.core.arp
                                 )
                             }
+                            "memoryMap" => {
+                                self.memory.map.dump();
+                            }
                             "stop" => {
                                 self.on = false;
                                 return;
@@ -704,19 +1456,236 @@ impl Machine {
                             _ => {}
                         }
                     }
+                } else if !self.debug && breakpoints.is_empty() {
+                    //nothing to check between instructions
+                    match self.backend {
+                        Backend::Interpreter => {
+                            while self.on {
+                                exec_cached(self);
+                                if self.core.cycles >= self.next_event {
+                                    self.tick();
+                                }
+                            }
+                        }
+                        Backend::Threaded => {
+                            while self.on {
+                                exec_block(self);
+                                if self.core.cycles >= self.next_event {
+                                    self.tick();
+                                }
+                            }
+                        }
+                    }
                 } else {
                     self.freq.0 += 1;
-                    exec_bytecode(self)
+                    exec_bytecode(self);
+                    if self.core.cycles >= self.next_event {
+                        self.tick();
+                    }
                 }
             }));
-            if result.is_err() {
+            if let Err(payload) = result {
+                self.fault = payload.downcast::<VmError>().ok().map(|err| match *err {
+                    VmError::StackOverflow { depth, limit, .. } => VmError::StackOverflow {
+                        ip: self.inst_ip,
+                        depth,
+                        limit,
+                    },
+                    VmError::StackUnderflow { depth, .. } => VmError::StackUnderflow {
+                        ip: self.inst_ip,
+                        depth,
+                    },
+                    err => err,
+                });
+                self.fault_trace = backtrace(self, true);
                 self.panic(self.core.ip);
                 return;
             }
         }
     }
+    fn tick(&mut self) {
+        if self.core.cycles >= self.throttle.next_check {
+            self.sync_clock();
+        }
+        if self.core.cycles >= self.sched.slice_end {
+            self.switch_core();
+        }
+        if self.core.cycles >= self.next_device_tick {
+            self.next_device_tick = self.core.cycles + DEVICE_TICK;
+            devices::tick(self);
+        }
+        self.update_next_event();
+    }
+    fn update_next_event(&mut self) {
+        self.next_event = self
+            .throttle
+            .next_check
+            .min(self.sched.slice_end)
+            .min(self.next_device_tick);
+    }
+    //Parks the running core at the back of the queue and runs the one at the front
+    fn switch_core(&mut self) {
+        if !self.sched.waiting.is_empty() {
+            self.wake_cores();
+        }
+        if let Some(next) = self.sched.parked.pop_front() {
+            let prev = std::mem::replace(&mut self.core, next);
+            self.core.cycles = prev.cycles;
+            self.sched.parked.push_back(prev);
+        }
+        self.end_slice_in(CORE_SLICE);
+    }
+    //Drops the running core. Core 0 is parked or waiting whenever another one runs, so there's
+    //always a core to switch to once it wakes up.
+    fn halt_core(&mut self) {
+        while self.sched.parked.is_empty() {
+            let wake = self.sched.waiting.iter().map(|(_, wake)| *wake).min();
+            self.idle_until(wake.expect("no core left to run"));
+            self.wake_cores();
+        }
+        let next = self.sched.parked.pop_front().expect("no core left to run");
+        let prev = std::mem::replace(&mut self.core, next);
+        self.core.cycles = prev.cycles;
+        self.end_slice_in(CORE_SLICE);
+    }
+    //Moves the running core to waiting until the next frame boundary and runs another one. With
+    //nothing else to run the clock skips ahead to when a core is due, a device event wakes every
+    //core. Waking goes by cycles rather than the host's clock, so cores interleave the same way
+    //on every run.
+    fn wait_core(&mut self) {
+        let frame = self.clock_hz.unwrap_or(DEFAULT_CLOCK_HZ) / FRAMES_PER_SEC;
+        let wake = (self.core.cycles / frame + 1) * frame;
+        loop {
+            if self.wake_cores() {
+                return;
+            }
+            if !self.sched.parked.is_empty() {
+                break;
+            }
+            if self.core.cycles >= wake {
+                return;
+            }
+            let first = self.sched.waiting.iter().map(|(_, wake)| *wake).min();
+            self.idle_until(first.map_or(wake, |first| first.min(wake)));
+        }
+        let next = self.sched.parked.pop_front().expect("no core left to run");
+        let prev = std::mem::replace(&mut self.core, next);
+        self.core.cycles = prev.cycles;
+        self.sched.waiting.push((prev, wake));
+        self.end_slice_in(CORE_SLICE);
+    }
+    //Queues up the waiting cores that are due, or all of them when a device raised an event.
+    //Returns whether one did.
+    fn wake_cores(&mut self) -> bool {
+        let event = devices::poll_events(self);
+        let now = self.core.cycles;
+        let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.sched.waiting)
+            .into_iter()
+            .partition(|(_, wake)| event || *wake <= now);
+        self.sched.waiting = waiting;
+        self.sched.parked.extend(due.into_iter().map(|(core, _)| core));
+        event
+    }
+    //Runs the clock on to cycle wake while no core can run. When clock_hz is set the host first
+    //sleeps, IDLE_SLICE at a time, until the throttle says wake is due, which counts as idle
+    //time in freq.
+    fn idle_until(&mut self, wake: u64) {
+        if let Some(hz) = self.clock_hz {
+            let ahead = wake.saturating_sub(self.throttle.cycles);
+            let due = self.throttle.start + Duration::from_secs_f64(ahead as f64 / hz as f64);
+            let start = Instant::now();
+            if due > start {
+                thread::sleep((due - start).min(IDLE_SLICE));
+                self.freq.2 += start.elapsed();
+                if Instant::now() < due {
+                    return;
+                }
+            }
+        }
+        self.core.cycles = self.core.cycles.max(wake);
+    }
+    //a fresh context for another core or a coroutine
+    pub(crate) fn new_core(&self) -> Core {
+        Core::new(self.memory.stack_base(), self.stack_limit)
+    }
+    pub fn start_core(&mut self, entry: usize, arg: i32) -> Option<usize> {
+        if self.sched.parked.len() + self.sched.waiting.len() + 1 >= MAX_CORES {
+            return None;
+        }
+        let mut core = self.new_core();
+        core.id = self.sched.next_id;
+        core.ip = entry;
+        core.stack.push(DataType::Int32(arg), &mut core.srp);
+        self.sched.next_id += 1;
+        self.sched.parked.push_back(core);
+        if self.sched.slice_end == u64::MAX {
+            self.end_slice_in(CORE_SLICE);
+        }
+        Some(self.sched.next_id - 1)
+    }
+    fn end_slice_in(&mut self, cycles: u64) {
+        //waiting cores need the slice end too, to be woken up on time
+        self.sched.slice_end = if self.sched.parked.is_empty() && self.sched.waiting.is_empty() {
+            u64::MAX
+        } else {
+            self.core.cycles + cycles
+        };
+        self.update_next_event();
+    }
+    //Sleeps off however far the guest got ahead of clock_hz. Checked every THROTTLE_SLICE of
+    //guest time so the fast loops only pay for one compare per instruction.
+    fn sync_clock(&mut self) {
+        let Some(hz) = self.clock_hz else {
+            self.throttle.next_check = u64::MAX;
+            return;
+        };
+        let throttle = &mut self.throttle;
+        let ran = self.core.cycles.saturating_sub(throttle.cycles);
+        let target = Duration::from_secs_f64(ran as f64 / hz as f64);
+        let elapsed = throttle.start.elapsed();
+        if target > elapsed {
+            let slept = Instant::now();
+            thread::sleep(target - elapsed);
+            self.freq.2 += slept.elapsed();
+        } else if elapsed - target > MAX_CLOCK_LAG {
+            //the host can't keep up, don't try to catch up in a burst later
+            throttle.start = Instant::now();
+            throttle.cycles = self.core.cycles;
+        }
+        throttle.next_check =
+            self.core.cycles + (hz as f64 * THROTTLE_SLICE.as_secs_f64()).max(1.0) as u64;
+    }
     pub fn set_disk(&mut self, disk: Disk) {
-        self.devices[0].contents = RawDevice::Disk(disk);
+        self.symbols = SymbolMap::from_disk(&disk);
+        self.devices[0] = Rc::new(RefCell::new(DiskDrive::new(disk)));
+    }
+    //Plugs in a device in the next free slot and returns its ID for IO and the MMIO window.
+    //Slots from MAX_DEVICES on still take IO but have no MMIO registers.
+    pub fn register_device(&mut self, device: impl Device) -> usize {
+        self.devices.push(Rc::new(RefCell::new(device)));
+        self.devices.len() - 1
+    }
+    //The device in slot id if it's a T, None while it's running a command
+    pub fn device<T: Device>(&self, id: usize) -> Option<Ref<'_, T>> {
+        let device = self.devices.get(id)?.try_borrow().ok()?;
+        Ref::filter_map(device, |d| (d as &dyn Any).downcast_ref::<T>()).ok()
+    }
+    //Lets guests call f through the host call device, plugging one in if there isn't one
+    //yet. Returns the id guests call it by.
+    pub fn register_host_fn(
+        &mut self,
+        name: &str,
+        f: impl FnMut(&mut Machine, &[i16]) -> HostResult + 'static,
+    ) -> usize {
+        let slot = (0..self.devices.len()).find(|&id| self.device::<HostCalls>(id).is_some());
+        let slot = slot.unwrap_or_else(|| self.register_device(HostCalls::new()));
+        self.device_mut::<HostCalls>(slot)
+            .expect("host call device")
+            .register(name, Box::new(f))
+    }
+    pub fn device_mut<T: Device>(&self, id: usize) -> Option<RefMut<'_, T>> {
+        let device = self.devices.get(id)?.try_borrow_mut().ok()?;
+        RefMut::filter_map(device, |d| (d as &mut dyn Any).downcast_mut::<T>()).ok()
     }
 }
 
@@ -733,12 +1702,19 @@ pub struct Core {
     pub f2: f32,
     pub srp: usize,
     pub arp: usize,
+    //cycles spent so far by the cost model, readable through CYC
+    pub cycles: u64,
+    //readable through CORE, 0 is the core the machine boots on
+    pub id: usize,
+    //handle of the coroutine this context belongs to
+    pub coroutine: Option<usize>,
 }
 impl Core {
-    fn new() -> Core {
+    //stack_base is where stack word 0 lives, right after memory
+    pub fn new(stack_base: usize, stack_limit: usize) -> Core {
         Core {
             ip: 0,
-            stack: Stack::new(),
+            stack: Stack::new(stack_limit),
             r1: 0,
             r2: 0,
             r3: 0,
@@ -747,7 +1723,10 @@ impl Core {
             f1: 0.0,
             f2: 0.0,
             srp: 0,
-            arp: 4 * 1024 * 1024,
+            arp: stack_base,
+            cycles: 0,
+            id: 0,
+            coroutine: None,
         }
     }
 }
@@ -755,33 +1734,106 @@ impl Core {
 pub struct Memory {
     data: Vec<i16>,
     max_size: usize,
+    pub map: MemoryMap,
+    pub icache: InstructionCache,
+    pub blocks: BlockCache,
+    mmio_base: usize,
+    //stores into the MMIO window, handed to the devices once the instruction is done
+    pub(crate) mmio_writes: Vec<(usize, i16)>,
 }
 impl Memory {
     fn new(max_size: usize) -> Memory {
         Memory {
             data: vec![0; max_size],
             max_size,
+            mmio_base: max_size - MMIO_LEN,
+            mmio_writes: vec![],
+            map: MemoryMap::boot(max_size),
+            icache: InstructionCache::new(),
+            blocks: BlockCache::new(),
         }
     }
+    //faults if the access isn't allowed, or if a stack address lies past the top of the stack
+    fn check(&self, index: usize, access: Access, stack: &Stack) {
+        if let Some(err) = self.access_error(index, access, stack) {
+            fault(err);
+        }
+    }
+    fn access_error(&self, index: usize, access: Access, stack: &Stack) -> Option<VmError> {
+        if !self.map.allows(index, access) {
+            return self.map.check(index, access).err();
+        }
+        if index >= self.max_size && index - self.max_size >= stack.len() {
+            return Some(VmError::Unmapped {
+                addr: index,
+                access,
+            });
+        }
+        None
+    }
     pub fn read(&self, index: usize, machine: &Machine) -> i16 {
+        self.check(index, Access::Read, &machine.core.stack);
+        self.read_unchecked(index, machine)
+    }
+    //reads an instruction word, which requires execute permission
+    pub fn fetch(&self, index: usize, machine: &Machine) -> i16 {
+        self.check(index, Access::Execute, &machine.core.stack);
+        self.read_unchecked(index, machine)
+    }
+    //fetch that returns the fault instead of raising it
+    pub fn try_fetch(&self, index: usize, machine: &Machine) -> Result<i16, VmError> {
+        match self.access_error(index, Access::Execute, &machine.core.stack) {
+            Some(err) => Err(err),
+            None => Ok(self.read_unchecked(index, machine)),
+        }
+    }
+    fn read_unchecked(&self, index: usize, machine: &Machine) -> i16 {
         if index >= self.max_size {
             //gotta allow multiple bytes
             machine.core.stack.read_bytes(index - self.max_size, 1)[0]
+        } else if index >= self.mmio_base {
+            devices::mmio_read(machine, index - self.mmio_base)
         } else {
             self.data.get(index).copied().unwrap_or(0)
         }
     }
+    //Same as range.len() single word accesses, with the check done once for the whole range
+    fn check_range(&self, range: Range<usize>, access: Access, stack: &Stack) {
+        //stack words exist up to the top of the stack
+        let top = self.max_size + stack.len();
+        let mapped = range.start..range.end.min(top.max(range.start));
+        if let Err(err) = self.map.check_range(mapped, access) {
+            fault(err);
+        }
+        if range.end > top {
+            fault(VmError::Unmapped {
+                addr: range.start.max(top),
+                access,
+            });
+        }
+    }
     pub fn read_range(&self, range: Range<usize>, machine: &Machine) -> Vec<i16> {
-        let mut result = Vec::new();
-        for i in range {
-            result.push(self.read(i, machine));
+        self.check_range(range.clone(), Access::Read, &machine.core.stack);
+        if range.end <= self.mmio_base {
+            return self.data[range].to_vec();
         }
-        result
+        if range.start >= self.max_size {
+            return machine
+                .core
+                .stack
+                .read_bytes(range.start - self.max_size, range.len());
+        }
+        range.map(|i| self.read_unchecked(i, machine)).collect()
     }
     pub fn write(&mut self, index: usize, value: i16, core: &mut Core) {
+        self.check(index, Access::Write, &core.stack);
         if index >= self.max_size {
             core.stack.write_bytes(index - self.max_size, vec![value])
+        } else if index >= self.mmio_base {
+            self.mmio_writes.push((index - self.mmio_base, value));
         } else {
+            self.icache.invalidate(index..index + 1);
+            self.blocks.invalidate(index..index + 1);
             if index < self.data.len() {
                 self.data[index] = value;
             } else {
@@ -791,153 +1843,362 @@ impl Memory {
         }
     }
     pub fn write_range(&mut self, range: Range<usize>, value: Vec<i16>, core: &mut Core) {
-        for (i, v) in range.zip(value) {
-            self.write(i, v, core);
+        let range = range.start..range.start + range.len().min(value.len());
+        self.check_range(range.clone(), Access::Write, &core.stack);
+        if range.is_empty() {
+            return;
+        }
+        if range.end <= self.mmio_base {
+            let len = range.len();
+            self.icache.invalidate(range.clone());
+            self.blocks.invalidate(range.clone());
+            self.data[range].copy_from_slice(&value[..len]);
+        } else if range.start >= self.max_size {
+            core.stack
+                .write_bytes(range.start - self.max_size, value[..range.len()].to_vec());
+        } else {
+            for (i, v) in range.zip(value) {
+                self.write(i, v, core);
+            }
         }
     }
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+    //memory address of stack word 0
+    pub fn stack_base(&self) -> usize {
+        self.max_size
+    }
+    //installs a region, only the loader is allowed to do this
+    fn map_region(&mut self, ip: usize, region: Region) {
+        match self.map.find(ip) {
+            Some(r) if r.kind == RegionKind::Loader => {
+                self.map.map(region);
+                //cached instructions were checked against the old permissions
+                self.icache.clear();
+                self.blocks.clear();
+            }
+            _ => fault(VmError::Privileged { ip }),
+        }
+    }
 }
+//Typed values as they are pushed onto the stack
 #[derive(Debug, Clone, Copy)]
 pub enum DataType {
     Float(f32),
     Int(i16),
     Int32(i32),
-    None,
-}
-#[derive(Debug)]
-pub struct Stack {
-    data: Vec<DataType>,
-}
-
-pub fn unpack_dt(i: DataType) -> f64 {
-    match i {
-        DataType::Float(f) => f as f64,
-        DataType::Int(i) => i as f64,
-        DataType::Int32(e) => e as f64,
-        _ => 0.0,
-    }
 }
 fn unpack_dt_to_bytes(i: DataType) -> Vec<i16> {
     match i {
         DataType::Float(f) => convert_float(f),
         DataType::Int(i) => vec![i],
         DataType::Int32(e) => convert_i32_to_i16(e).to_vec(),
-        _ => vec![0],
     }
 }
-fn dt_size(i: DataType) -> usize {
-    match i {
-        DataType::Float(_f) => 2,
-        DataType::Int32(_i) => 2,
-        DataType::Int(_i) => 1,
-        DataType::None => 1,
-    }
+//Flat, word addressed stack. Stack address n lives at memory address max_size+n,
+//i32s and f32s take two words (low word first), the same layout they have in memory.
+#[derive(Debug)]
+pub struct Stack {
+    data: Vec<i16>,
+    //words the stack may grow to before pushes raise StackOverflow
+    pub limit: usize,
+    //frames pushed by Call and not yet returned from
+    pub depth: usize,
+    //most words the stack has held
+    pub high_water: usize,
 }
+//1M words, well past anything but runaway recursion
+pub const DEFAULT_STACK_LIMIT: usize = 1 << 20;
 impl Stack {
-    fn new() -> Stack {
-        Stack { data: Vec::new() }
+    fn new(limit: usize) -> Stack {
+        Stack {
+            data: Vec::new(),
+            limit,
+            depth: 0,
+            high_water: 0,
+        }
     }
     pub fn len(&self) -> usize {
         self.data.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
 
     pub fn dump(&self) {
         self.data
             .chunks(16)
             .enumerate()
-            .for_each(|(i, x)| println!("%{}: {:?}", i, x));
+            .for_each(|(i, x)| println!("%{}: {:?}", i * 16, x));
+    }
+    //ip is filled in by Machine::run, the stack doesn't know where it was called from
+    fn overflow(&self) -> ! {
+        fault(VmError::StackOverflow {
+            ip: 0,
+            depth: self.depth,
+            limit: self.limit,
+        })
+    }
+    fn underflow(&self) -> ! {
+        fault(VmError::StackUnderflow {
+            ip: 0,
+            depth: self.depth,
+        })
+    }
+    //index - n, raising StackUnderflow instead of wrapping
+    pub fn below(&self, index: usize, n: usize) -> usize {
+        index.checked_sub(n).unwrap_or_else(|| self.underflow())
     }
     pub fn push(&mut self, x: DataType, srp: &mut usize) {
-        if *srp >= self.data.len() {
-            self.data.resize(*srp, DataType::None);
+        let words = unpack_dt_to_bytes(x);
+        let len = words.len();
+        if *srp + len > self.limit {
+            self.overflow();
         }
-        self.data.insert(*srp, x);
-        *srp += 1;
-    }
-    fn convert_byte_index_to_stack(&self, byte_index: usize) -> (usize, usize) {
-        let mut byte_count = 0;
-        for (i, dt) in self.data.iter().enumerate() {
-            let size = dt_size(*dt);
-            if byte_count + size > byte_index {
-                return (i, byte_index - byte_count);
-            }
-            byte_count += size;
+        if *srp > self.data.len() {
+            self.data.resize(*srp, 0);
         }
-        (0, 0)
+        self.data.splice(*srp..*srp, words);
+        *srp += len;
+        self.high_water = self.high_water.max(self.data.len());
+    }
+    //read_bytes that returns None instead of faulting
+    pub fn get(&self, index: usize, len: usize) -> Option<&[i16]> {
+        self.data.get(index..index + len)
     }
     pub fn read_bytes(&self, byte_index: usize, len: usize) -> Vec<i16> {
-        let mut bytes = Vec::new();
-        for i in byte_index..byte_index + len {
-            let (index, offset) = self.convert_byte_index_to_stack(i);
-            bytes.push(
-                unpack_dt_to_bytes(
-                    *self
-                        .data
-                        .get(index)
-                        .expect(format!("Failed to get index {} of stack", index).as_str()),
-                )[offset],
-            );
+        match self.data.get(byte_index..byte_index + len) {
+            Some(words) => words.to_vec(),
+            None => self.underflow(),
         }
-        bytes
     }
     pub fn write_bytes(&mut self, byte_index: usize, bytes: Vec<i16>) {
-        for i in byte_index..byte_index + bytes.len() {
-            let (index, offset) = self.convert_byte_index_to_stack(i);
-
-            *self
-                .data
-                .get_mut(index)
-                .expect(format!("Failed to get index {} of stack", index).as_str()) =
-                match *self
-                    .data
-                    .get(index)
-                    .expect(format!("Failed to get index {} of stack", index).as_str())
-                {
-                    DataType::Int(_i) => DataType::Int(bytes[i - byte_index]),
-                    DataType::Float(_f) => {
-                        let mut unpacked =
-                            unpack_dt_to_bytes(*self.data.get(index).expect(
-                                format!("Failed to get index {} of stack", index).as_str(),
-                            ));
-                        unpacked[offset] = bytes[i - byte_index];
-                        DataType::Float(
-                            unpack_float(unpacked.as_slice()).expect("Couldn't write to stack"),
-                        )
-                    }
-                    DataType::Int32(_e) => {
-                        let mut unpacked =
-                            unpack_dt_to_bytes(*self.data.get(index).expect(
-                                format!("Failed to get index {} of stack", index).as_str(),
-                            ));
-                        unpacked[offset] = bytes[i - byte_index];
-                        DataType::Int32(convert_i16_to_i32(unpacked.as_slice()))
-                    }
-                    _ => DataType::Int(bytes[i - byte_index]),
-                };
+        match self.data.get_mut(byte_index..byte_index + bytes.len()) {
+            Some(words) => words.copy_from_slice(&bytes),
+            None => self.overflow(),
         }
     }
-    pub fn pop(&mut self, srp: &mut usize) -> DataType {
-        self.remove(*srp - 1, srp)
+    //pops a single word
+    pub fn pop(&mut self, srp: &mut usize) -> i16 {
+        self.remove(self.below(*srp, 1), srp)
+    }
+    pub fn pop_i32(&mut self, srp: &mut usize) -> i32 {
+        self.remove_i32(self.below(*srp, 2), srp)
+    }
+    pub fn pop_f32(&mut self, srp: &mut usize) -> f32 {
+        let bytes = self.read_bytes(self.below(*srp, 2), 2);
+        self.pop_range(*srp - 2..*srp, srp);
+        unpack_float(&bytes).expect("Couldn't pop float")
     }
     pub fn pop_range(&mut self, range: std::ops::Range<usize>, srp: &mut usize) {
+        if range.end > self.data.len() || range.len() > *srp {
+            self.underflow();
+        }
         let rlen = range.len();
         self.data.drain(range);
         *srp -= rlen;
     }
-    pub fn remove(&mut self, index: usize, srp: &mut usize) -> DataType {
+    pub fn remove(&mut self, index: usize, srp: &mut usize) -> i16 {
+        if index >= self.data.len() || *srp == 0 {
+            self.underflow();
+        }
         *srp -= 1;
         self.data.remove(index)
     }
+    pub fn remove_i32(&mut self, index: usize, srp: &mut usize) -> i32 {
+        let val = convert_i16_to_i32(&self.read_bytes(index, 2));
+        self.pop_range(index..index + 2, srp);
+        val
+    }
     pub fn resize(&mut self, size: usize, srp: &mut usize) {
+        if size > self.limit {
+            self.overflow();
+        }
         if size <= self.data.len() {
             *srp = size;
         }
-        self.data.resize(size, DataType::None);
+        self.data.resize(size, 0);
+        self.high_water = self.high_water.max(size);
     }
 }
 
+impl CommandType {
+    //Base cost in cycles. MemCopy/MemSet/MemCmp add a cycle per word and IO adds the
+    //device's cost for the command, see Device::cycles.
+    pub fn cycles(&self) -> u64 {
+        match self {
+            CommandType::NOP
+            | CommandType::Mov
+            | CommandType::Add
+            | CommandType::Sub
+            | CommandType::And
+            | CommandType::Or
+            | CommandType::Xor
+            | CommandType::Not
+            | CommandType::Greater
+            | CommandType::LessThan
+            | CommandType::Exit
+            | CommandType::Wait => 1,
+            CommandType::AddEx
+            | CommandType::SubEx
+            | CommandType::AndEx
+            | CommandType::OrEx
+            | CommandType::XorEx
+            | CommandType::NotEx
+            | CommandType::ShlEx
+            | CommandType::ShrEx
+            | CommandType::ShrUEx
+            | CommandType::GreaterEx
+            | CommandType::LessThanEx
+            | CommandType::EqualEx
+            | CommandType::Load
+            | CommandType::Store
+            | CommandType::Push
+            | CommandType::Pop
+            | CommandType::Jump
+            | CommandType::JumpZero
+            | CommandType::JumpNotZero
+            | CommandType::Enter
+            | CommandType::Leave
+            | CommandType::CoStatus => 2,
+            CommandType::LoadEx
+            | CommandType::StoreEx
+            | CommandType::Loadf
+            | CommandType::Storef
+            | CommandType::Savef
+            | CommandType::Restoref
+            | CommandType::PushEx
+            | CommandType::PopEx
+            | CommandType::Pushf
+            | CommandType::Popf
+            | CommandType::JumpIndirect => 3,
+            CommandType::Mul
+            | CommandType::Addf
+            | CommandType::Subf
+            | CommandType::Absf
+            | CommandType::Minf
+            | CommandType::Maxf
+            | CommandType::Floor
+            | CommandType::Greaterf
+            | CommandType::LessThanf
+            | CommandType::Equalf
+            | CommandType::IntToFloat
+            | CommandType::FloatToInt
+            | CommandType::MemCopy
+            | CommandType::MemSet
+            | CommandType::MemCmp
+            | CommandType::IO
+            | CommandType::Switch
+            | CommandType::MapRegion
+            | CommandType::Cas
+            | CommandType::FetchAdd
+            | CommandType::QueryDevice => 4,
+            CommandType::Mulf | CommandType::MulEx => 6,
+            CommandType::Call
+            | CommandType::CallIndirect
+            | CommandType::Return
+            | CommandType::Ret
+            | CommandType::StartCore
+            | CommandType::CoCreate
+            | CommandType::CoResume
+            | CommandType::CoYield => 8,
+            CommandType::Div | CommandType::Mod | CommandType::Divf => 12,
+            CommandType::DivEx | CommandType::ModEx | CommandType::Sqrt => 20,
+            CommandType::Sin | CommandType::Cos | CommandType::Atan2 | CommandType::Pow => 40,
+            //registers aren't commands
+            _ => 1,
+        }
+    }
+    //(value operands, destination registers) that follow the command
+    pub fn operand_counts(&self) -> (usize, usize) {
+        match self {
+            CommandType::Add
+            | CommandType::Sub
+            | CommandType::Mul
+            | CommandType::Div
+            | CommandType::Mod
+            | CommandType::Addf
+            | CommandType::Subf
+            | CommandType::Mulf
+            | CommandType::Divf
+            | CommandType::AddEx
+            | CommandType::SubEx
+            | CommandType::MulEx
+            | CommandType::DivEx
+            | CommandType::And
+            | CommandType::Or
+            | CommandType::Xor
+            | CommandType::Greater
+            | CommandType::LessThan
+            | CommandType::Atan2
+            | CommandType::Pow
+            | CommandType::Minf
+            | CommandType::Maxf
+            | CommandType::Greaterf
+            | CommandType::LessThanf
+            | CommandType::Equalf
+            | CommandType::AndEx
+            | CommandType::OrEx
+            | CommandType::XorEx
+            | CommandType::ModEx
+            | CommandType::ShlEx
+            | CommandType::ShrEx
+            | CommandType::ShrUEx
+            | CommandType::GreaterEx
+            | CommandType::LessThanEx
+            | CommandType::EqualEx
+            | CommandType::Ret
+            | CommandType::Store
+            | CommandType::StoreEx
+            | CommandType::Storef
+            | CommandType::JumpNotZero
+            | CommandType::JumpZero
+            | CommandType::IO
+            | CommandType::FetchAdd
+            | CommandType::StartCore
+            | CommandType::CoCreate
+            | CommandType::QueryDevice => (2, 0),
+            CommandType::Not
+            | CommandType::NotEx
+            | CommandType::Sqrt
+            | CommandType::Sin
+            | CommandType::Cos
+            | CommandType::Floor
+            | CommandType::Absf
+            | CommandType::Push
+            | CommandType::Pushf
+            | CommandType::PushEx
+            | CommandType::Jump
+            | CommandType::JumpIndirect
+            | CommandType::Call
+            | CommandType::CallIndirect
+            | CommandType::Enter
+            | CommandType::CoResume
+            | CommandType::CoYield
+            | CommandType::CoStatus => (1, 0),
+            CommandType::Pop | CommandType::PopEx | CommandType::Popf => (0, 1),
+            CommandType::Load
+            | CommandType::LoadEx
+            | CommandType::Loadf
+            | CommandType::Mov
+            | CommandType::IntToFloat
+            | CommandType::FloatToInt
+            | CommandType::Savef
+            | CommandType::Restoref => (1, 1),
+            CommandType::Return
+            | CommandType::MemCopy
+            | CommandType::MemSet
+            | CommandType::MemCmp
+            | CommandType::Cas => (3, 0),
+            CommandType::MapRegion | CommandType::Switch => (4, 0),
+            _ => (0, 0),
+        }
+    }
+}
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum CommandType {
@@ -987,9 +2248,61 @@ pub enum CommandType {
     R5,
     EX1,
     EX2,
+    //cycle counter, read only, wraps at 32 bits
+    CYC,
+    //id of the running core, read only
+    CORE,
     NOP,
     IO,
     Loadf,
     Call,
     Return,
+    MapRegion,
+    PopEx,
+    Popf,
+    MemCopy,
+    MemSet,
+    MemCmp,
+    CallIndirect,
+    JumpIndirect,
+    Switch,
+    Sqrt,
+    Sin,
+    Cos,
+    Atan2,
+    Pow,
+    Floor,
+    Absf,
+    Minf,
+    Maxf,
+    Greaterf,
+    LessThanf,
+    Equalf,
+    IntToFloat,
+    FloatToInt,
+    Savef,
+    Restoref,
+    AndEx,
+    OrEx,
+    XorEx,
+    NotEx,
+    ModEx,
+    ShlEx,
+    ShrEx,
+    ShrUEx,
+    GreaterEx,
+    LessThanEx,
+    EqualEx,
+    Enter,
+    Leave,
+    Ret,
+    Wait,
+    Cas,
+    FetchAdd,
+    StartCore,
+    CoCreate,
+    CoResume,
+    CoYield,
+    CoStatus,
+    QueryDevice,
 }